@@ -1,5 +1,5 @@
 #![allow(unused)]
-mod example_strings;
+pub mod example_strings;
 mod example_deref;
 mod example_asref;
 mod example_iter;
\ No newline at end of file
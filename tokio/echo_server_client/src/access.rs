@@ -0,0 +1,164 @@
+use std::net::IpAddr;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum IpNetParseError {
+    #[error("InvalidAddress, address={0}")]
+    InvalidAddress(String),
+
+    #[error("InvalidPrefixLength, prefix={0}")]
+    InvalidPrefixLength(String),
+}
+
+/// Single IP address or CIDR range of IPv4 or IPv6 addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    address: IpAddr,
+    prefix_length: u8,
+}
+
+impl IpNet {
+    /// Range of addresses sharing first prefix_length bits with address
+    pub fn new(address: IpAddr, prefix_length: u8) -> Result<Self, IpNetParseError> {
+        if prefix_length > max_prefix_length(&address) {
+            return Err(IpNetParseError::InvalidPrefixLength(prefix_length.to_string()));
+        }
+        Ok(Self { address, prefix_length })
+    }
+
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        // IPv4 client of dual stack listener is seen as IPv4-mapped IPv6 address
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                prefix_matches(u32::from(network).into(), u32::from(address).into(), self.prefix_length, 32)
+            },
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                prefix_matches(u128::from(network), u128::from(address), self.prefix_length, 128)
+            },
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpNet {
+    fn from(address: IpAddr) -> Self {
+        Self { address, prefix_length: max_prefix_length(&address) }
+    }
+}
+
+impl std::str::FromStr for IpNet {
+    type Err = IpNetParseError;
+
+    /// Parse address like 192.168.0.1 or range like 10.0.0.0/8 and fd00::/8
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_length) = match value.split_once('/') {
+            Some((address, prefix_length)) => (address, Some(prefix_length)),
+            None => (value, None),
+        };
+        let address = address.parse::<IpAddr>()
+            .map_err(|_| IpNetParseError::InvalidAddress(address.to_string()))?;
+
+        match prefix_length {
+            Some(prefix_length) => {
+                let prefix_length = prefix_length.parse()
+                    .map_err(|_| IpNetParseError::InvalidPrefixLength(prefix_length.to_string()))?;
+                Self::new(address, prefix_length)
+            },
+            None => Ok(Self::from(address)),
+        }
+    }
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_length)
+    }
+}
+
+fn max_prefix_length(address: &IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn prefix_matches(network: u128, address: u128, prefix_length: u8, bits: u8) -> bool {
+    let ignored_bits = u32::from(bits - prefix_length);
+    network.checked_shr(ignored_bits).unwrap_or(0) == address.checked_shr(ignored_bits).unwrap_or(0)
+}
+
+/// Which peers may connect, checked right after accepting connection.
+/// Deny rules win over allow rules, with no allow rules every peer not denied is allowed.
+/// Clients connected over Unix domain socket are not checked.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessRules {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl AccessRules {
+    pub fn with_allow(mut self, network: IpNet) -> Self {
+        self.allow.push(network);
+        self
+    }
+
+    pub fn with_deny(mut self, network: IpNet) -> Self {
+        self.deny.push(network);
+        self
+    }
+
+    pub fn is_allowed(&self, address: &IpAddr) -> bool {
+        if self.deny.iter().any(|network| network.contains(address)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|network| network.contains(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_networks() {
+        assert_eq!("10.0.0.0/8".parse::<IpNet>().unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!("192.168.1.7".parse::<IpNet>().unwrap().to_string(), "192.168.1.7/32");
+        assert_eq!("fd00::/8".parse::<IpNet>().unwrap().to_string(), "fd00::/8");
+        assert_eq!("::1".parse::<IpNet>().unwrap().to_string(), "::1/128");
+
+        assert_eq!("10.0.0.0/33".parse::<IpNet>(), Err(IpNetParseError::InvalidPrefixLength("33".to_string())));
+        assert_eq!("10.0.0.0/x".parse::<IpNet>(), Err(IpNetParseError::InvalidPrefixLength("x".to_string())));
+        assert_eq!("localhost".parse::<IpNet>(), Err(IpNetParseError::InvalidAddress("localhost".to_string())));
+    }
+
+    #[test]
+    fn test_network_contains() {
+        let network = "10.1.0.0/16".parse::<IpNet>().unwrap();
+        assert!(network.contains(&ip("10.1.255.3")));
+        assert!(!network.contains(&ip("10.2.0.1")));
+        assert!(network.contains(&ip("::ffff:10.1.0.1")));
+        assert!(!network.contains(&ip("::1")));
+
+        let network = "fd00::/8".parse::<IpNet>().unwrap();
+        assert!(network.contains(&ip("fd12::1")));
+        assert!(!network.contains(&ip("fe80::1")));
+
+        assert!("0.0.0.0/0".parse::<IpNet>().unwrap().contains(&ip("203.0.113.9")));
+        assert!("::/0".parse::<IpNet>().unwrap().contains(&ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn test_deny_wins_over_allow() {
+        let rules = AccessRules::default()
+            .with_allow("10.0.0.0/8".parse().unwrap())
+            .with_deny("10.0.0.13".parse().unwrap());
+        assert!(rules.is_allowed(&ip("10.0.0.12")));
+        assert!(!rules.is_allowed(&ip("10.0.0.13")));
+        assert!(!rules.is_allowed(&ip("192.168.0.1")));
+
+        assert!(AccessRules::default().is_allowed(&ip("192.168.0.1")));
+    }
+}
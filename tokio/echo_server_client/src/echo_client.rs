@@ -0,0 +1,798 @@
+use std::{collections::VecDeque, pin::Pin, sync::{Arc, Mutex}, task::{ready, Context, Poll}};
+
+use bytes::Bytes;
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
+
+use crate::{
+    echo_server::{PUSH_PREFIX, SHUTDOWN_NOTICE},
+    framing::{Frame, FrameOverflowPolicy, FrameReader, Framing, DEFAULT_MAX_FRAME_LENGTH},
+    reconnect::{ReconnectHandler, ReconnectPolicy},
+    pubsub::{Publication, PUBLISHED_NOTICE, PUBLISH_COMMAND, SUBSCRIBED_NOTICE, SUBSCRIBE_COMMAND, UNSUBSCRIBED_NOTICE, UNSUBSCRIBE_COMMAND},
+    tls::{rustls, TlsConfigError},
+    transport::Transport,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum EchoClientError {
+    #[error("IoError, reason={0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("IoError, reason={0}")]
+    TimeoutPassed(#[from] tokio::time::error::Elapsed),
+
+    #[error("BadResponse received='{0}'")]
+    BadResponse(String),
+
+    #[error("BadBytesResponse received={0:?}")]
+    BadBytesResponse(Vec<u8>),
+
+    #[error("TlsConfigError, reason={0}")]
+    TlsConfigError(#[from] TlsConfigError),
+
+    #[error("FrameTooLong")]
+    FrameTooLong,
+
+    #[error("FramingMismatch, expected={0:?}")]
+    FramingMismatch(Framing),
+
+    #[error("NoResponse, attempts={attempts}")]
+    NoResponse {
+        attempts: u32,
+    },
+
+    #[error("ReconnectAttemptsExhausted, attempts={attempts}")]
+    ReconnectAttemptsExhausted {
+        attempts: u32,
+    },
+}
+
+/// Plain or TLS encrypted connection to echo server
+enum ClientStream {
+    Plain(Transport),
+    Tls(Box<tokio_rustls::client::TlsStream<Transport>>),
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Where client connects, kept to be able to connect again
+enum Endpoint {
+    Tcp(std::net::SocketAddr),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+    Tls {
+        address: std::net::SocketAddr,
+        server_name: rustls::pki_types::ServerName<'static>,
+        connector: tokio_rustls::TlsConnector,
+    },
+}
+
+impl Endpoint {
+    async fn connect(&self) -> Result<ClientStream, EchoClientError> {
+        match self {
+            Endpoint::Tcp(address) => Ok(ClientStream::Plain(Transport::Tcp(tokio::net::TcpStream::connect(address).await?))),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Ok(ClientStream::Plain(Transport::Unix(tokio::net::UnixStream::connect(path).await?))),
+            Endpoint::Tls { address, server_name, connector } => {
+                let socket = Transport::Tcp(tokio::net::TcpStream::connect(address).await?);
+                let tls_stream = connector.connect(server_name.clone(), socket).await?;
+                Ok(ClientStream::Tls(Box::new(tls_stream)))
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp(address) | Endpoint::Tls { address, .. } => write!(f, "{address}"),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+pub struct EchoClient {
+    reader: FrameReader<ReadHalf<ClientStream>>,
+    /// Taken by sender half while client is split
+    writer: Option<WriteHalf<ClientStream>>,
+    framing: Framing,
+    publications: VecDeque<Publication>,
+    pushed: VecDeque<Bytes>,
+    /// Replies which arrived while waiting for pushed message
+    replies: VecDeque<Vec<u8>>,
+    /// Messages sent by split sender whose replies were not read yet
+    sent: Arc<Mutex<VecDeque<String>>>,
+    /// Request was interrupted, partial frame or unread replies may be left on connection
+    broken: bool,
+    span: tracing::Span,
+    endpoint: Endpoint,
+    reconnect_policy: Option<ReconnectPolicy>,
+    reconnect_handler: Option<Box<dyn ReconnectHandler>>,
+}
+
+impl EchoClient {
+    pub async fn new<A: tokio::net::ToSocketAddrs>(addr: A) -> Result<Self, EchoClientError> {
+        let socket = tokio::net::TcpStream::connect(addr).await?;
+        let endpoint = Endpoint::Tcp(socket.peer_addr()?);
+        Ok(Self::from_stream(ClientStream::Plain(Transport::Tcp(socket)), endpoint))
+    }
+
+    /// Connect to echo server listening on Unix domain socket path
+    #[cfg(unix)]
+    pub async fn new_unix<P: AsRef<std::path::Path>>(path: P) -> Result<Self, EchoClientError> {
+        let endpoint = Endpoint::Unix(path.as_ref().to_path_buf());
+        let socket = tokio::net::UnixStream::connect(path).await?;
+        Ok(Self::from_stream(ClientStream::Plain(Transport::Unix(socket)), endpoint))
+    }
+
+    /// Connect over TLS, server certificate is verified against given root store and server name
+    pub async fn new_tls<A: tokio::net::ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        root_store: rustls::RootCertStore
+    ) -> Result<Self, EchoClientError> {
+        let connector = crate::tls::make_connector(root_store)?;
+        let server_name = crate::tls::make_server_name(server_name)?;
+        let socket = tokio::net::TcpStream::connect(addr).await?;
+        let address = socket.peer_addr()?;
+
+        let tls_stream = connector.connect(server_name.clone(), Transport::Tcp(socket)).await?;
+        let endpoint = Endpoint::Tls { address, server_name, connector };
+        Ok(Self::from_stream(ClientStream::Tls(Box::new(tls_stream)), endpoint))
+    }
+
+    fn from_stream(stream: ClientStream, endpoint: Endpoint) -> Self {
+        let span = tracing::info_span!("echo_client", peer_addr = %endpoint);
+        tracing::debug!(parent: &span, tls = matches!(stream, ClientStream::Tls(_)), "Connected");
+        let (reader, writer) = tokio::io::split(stream);
+        Self {
+            reader: FrameReader::new(reader, Framing::Line, Some(DEFAULT_MAX_FRAME_LENGTH), FrameOverflowPolicy::Disconnect),
+            writer: Some(writer),
+            framing: Framing::Line,
+            publications: VecDeque::new(),
+            pushed: VecDeque::new(),
+            replies: VecDeque::new(),
+            sent: Arc::default(),
+            broken: false,
+            span,
+            endpoint,
+            reconnect_policy: None,
+            reconnect_handler: None,
+        }
+    }
+
+    /// Reconnect automatically when connection to server is lost, failed request is sent again after reconnecting.
+    /// Request may be delivered twice, server could have processed it before connection was lost.
+    pub fn with_reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = Some(policy);
+        self
+    }
+
+    /// Handler called after every successful reconnect
+    pub fn with_reconnect_handler<H: ReconnectHandler>(mut self, handler: H) -> Self {
+        self.reconnect_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Connect again following reconnect policy, replies which were not read yet are lost
+    async fn reconnect(&mut self) -> Result<(), EchoClientError> {
+        let Some(policy) = self.reconnect_policy else {
+            return Err(EchoClientError::ReconnectAttemptsExhausted { attempts: 0 });
+        };
+
+        for attempt in 1..=policy.max_attempts() {
+            tokio::time::sleep(policy.jittered_delay(attempt)).await;
+            let stream = match self.endpoint.connect().await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::debug!(parent: &self.span, attempt, error = %e, "Reconnect attempt failed");
+                    continue;
+                },
+            };
+
+            tracing::info!(parent: &self.span, attempt, "Reconnected");
+            let (reader, writer) = tokio::io::split(stream);
+            self.reader = FrameReader::new(reader, self.framing, Some(DEFAULT_MAX_FRAME_LENGTH), FrameOverflowPolicy::Disconnect);
+            self.writer = Some(writer);
+            self.replies.clear();
+            self.sent.lock().unwrap().clear();
+            self.broken = false;
+
+            // Handler is taken out, so connection lost inside of it does not call it recursively
+            if let Some(mut handler) = self.reconnect_handler.take() {
+                let result = handler.on_reconnect(self).await;
+                self.reconnect_handler = Some(handler);
+                result?;
+            }
+            return Ok(());
+        }
+
+        tracing::warn!(parent: &self.span, attempts = policy.max_attempts(), "Reconnect attempts exhausted");
+        Err(EchoClientError::ReconnectAttemptsExhausted { attempts: policy.max_attempts() })
+    }
+
+    /// Reconnect if request failed because connection was lost and client has reconnect policy,
+    /// true means request should be run once more
+    async fn reconnect_for_retry<T>(&mut self, result: &Result<T, EchoClientError>) -> Result<bool, EchoClientError> {
+        match result {
+            Err(EchoClientError::IoError(e)) if self.reconnect_policy.is_some() => {
+                tracing::info!(parent: &self.span, error = %e, "Connection lost, reconnecting");
+                self.reconnect().await?;
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+
+    /// Select how messages are delimited, has to match server framing
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self.reader.set_framing(framing);
+        self
+    }
+
+    fn writer(&mut self) -> &mut WriteHalf<ClientStream> {
+        self.writer.as_mut().expect("split client is used only through its halves")
+    }
+
+    async fn write_frame(&mut self, msg: &[u8]) -> Result<(), EchoClientError> {
+        self.check_broken()?;
+        let frame = encode_message(self.framing, msg);
+        tracing::trace!(parent: &self.span, length = frame.len(), "Sending frame");
+        self.broken = true;
+        self.writer().write_all(&frame).await?;
+        self.writer().flush().await?;
+        self.broken = false;
+        Ok(())
+    }
+
+    /// Writer and reading state borrowed apart, so both can be used at once
+    fn split_borrow(&mut self) -> (Option<&mut WriteHalf<ClientStream>>, ReadView<'_>) {
+        let reading = ReadView {
+            reader: &mut self.reader,
+            pushed: &mut self.pushed,
+            replies: &mut self.replies,
+            framing: self.framing,
+            eof_is_error: self.reconnect_policy.is_some(),
+            span: &self.span,
+        };
+        (self.writer.as_mut(), reading)
+    }
+
+    fn reading(&mut self) -> ReadView<'_> {
+        self.split_borrow().1
+    }
+
+    async fn read_raw_frame(&mut self, expect_text: bool) -> Result<Option<Vec<u8>>, EchoClientError> {
+        self.reading().read_raw_frame(expect_text).await
+    }
+
+    async fn read_frame(&mut self, expect_text: bool, awaited: Option<&[u8]>) -> Result<Option<Vec<u8>>, EchoClientError> {
+        self.reading().read_frame(expect_text, awaited).await
+    }
+
+    async fn read_line(&mut self, awaited: Option<&str>) -> Result<Option<String>, EchoClientError> {
+        self.reading().read_line(awaited).await
+    }
+
+    /// Fail request on connection left in unknown state by interrupted one
+    fn check_broken(&self) -> Result<(), EchoClientError> {
+        if self.broken {
+            return Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "previous request was interrupted").into());
+        }
+        Ok(())
+    }
+
+    /// Send single framed message and read one frame back, reconnecting once if connection was lost
+    async fn exchange(
+        &mut self,
+        timeout: Option<std::time::Duration>,
+        msg: &[u8],
+        expect_text: bool,
+    ) -> Result<Vec<u8>, EchoClientError> {
+        let result = self.exchange_once(timeout, msg, expect_text).await;
+        if self.reconnect_for_retry(&result).await? {
+            return self.exchange_once(timeout, msg, expect_text).await;
+        }
+        result
+    }
+
+    async fn exchange_once(
+        &mut self,
+        timeout: Option<std::time::Duration>,
+        msg: &[u8],
+        expect_text: bool,
+    ) -> Result<Vec<u8>, EchoClientError> {
+        self.write_frame(msg).await?;
+
+        let response = if let Some(timeout_duration) = timeout {
+            tokio::time::timeout(timeout_duration, self.read_frame(expect_text, Some(msg))).await??
+        } else {
+            self.read_frame(expect_text, Some(msg)).await?
+        };
+        Ok(response.unwrap_or_default())
+    }
+
+    pub async fn send_await(
+        &mut self, 
+        timeout: Option<std::time::Duration>, 
+        msg: &str
+    ) -> Result<(), EchoClientError> {
+        let response = self.exchange(timeout, msg.as_bytes(), true).await?;
+        let buf = String::from_utf8_lossy(&response).into_owned();
+        if msg != buf.trim_end() {
+            Err(EchoClientError::BadResponse(buf))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Send raw bytes and wait for the same bytes to be echoed back.
+    /// With line framing payload should not contain newline, it would be echoed as separate messages.
+    pub async fn send_await_bytes(
+        &mut self,
+        timeout: Option<std::time::Duration>,
+        msg: &[u8]
+    ) -> Result<(), EchoClientError> {
+        let response = self.exchange(timeout, msg, false).await?;
+        let echoed = match self.framing {
+            Framing::Line => response.strip_suffix(b"\n"),
+            _ => Some(&response[..]),
+        };
+
+        if echoed != Some(msg) {
+            Err(EchoClientError::BadBytesResponse(response))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Send all messages without waiting for replies and check replies arrive in the same order.
+    /// Replies are read while messages are still being written, timeout covers whole exchange.
+    /// Whole batch is sent again if connection was lost and client reconnected.
+    pub async fn send_pipelined(
+        &mut self,
+        timeout: Option<std::time::Duration>,
+        msgs: &[&str],
+    ) -> Result<(), EchoClientError> {
+        let result = self.send_pipelined_once(timeout, msgs).await;
+        if self.reconnect_for_retry(&result).await? {
+            return self.send_pipelined_once(timeout, msgs).await;
+        }
+        result
+    }
+
+    async fn send_pipelined_once(
+        &mut self,
+        timeout: Option<std::time::Duration>,
+        msgs: &[&str],
+    ) -> Result<(), EchoClientError> {
+        let frames = msgs.iter().flat_map(|msg| encode_message(self.framing, msg.as_bytes())).collect::<Vec<_>>();
+        tracing::trace!(parent: &self.span, messages = msgs.len(), length = frames.len(), "Sending pipelined frames");
+
+        // Until all replies are read connection is left in unknown state when exchange is interrupted or cancelled
+        self.check_broken()?;
+        self.broken = true;
+
+        // Writing is not waited for before reading, large batch would fill socket buffers of both sides
+        let (writer, mut reading) = self.split_borrow();
+        let writer = writer.expect("split client is used only through its halves");
+        let write = async {
+            writer.write_all(&frames).await?;
+            writer.flush().await?;
+            Ok(())
+        };
+        let await_replies = async {
+            for msg in msgs {
+                let response = reading.read_line(Some(msg)).await?.unwrap_or_default();
+                if *msg != response.trim_end() {
+                    return Err(EchoClientError::BadResponse(response));
+                }
+            }
+            Ok(())
+        };
+        let exchange = async { tokio::try_join!(write, await_replies).map(|_| ()) };
+
+        let result = if let Some(timeout_duration) = timeout {
+            tokio::time::timeout(timeout_duration, exchange).await.unwrap_or_else(|elapsed| Err(elapsed.into()))
+        } else {
+            exchange.await
+        };
+        self.broken = result.is_err();
+        result
+    }
+
+    /// Send pub/sub command and wait for its reply, reconnecting once if connection was lost
+    async fn pubsub_command(&mut self, timeout: Option<std::time::Duration>, command: &str) -> Result<String, EchoClientError> {
+        let result = self.pubsub_command_once(timeout, command).await;
+        if self.reconnect_for_retry(&result).await? {
+            return self.pubsub_command_once(timeout, command).await;
+        }
+        result
+    }
+
+    /// Send pub/sub command and wait for its reply, publications arriving meanwhile are kept for later
+    async fn pubsub_command_once(&mut self, timeout: Option<std::time::Duration>, command: &str) -> Result<String, EchoClientError> {
+        self.write_frame(command.as_bytes()).await?;
+
+        let await_reply = async {
+            loop {
+                let line = self.read_line(None).await?
+                    .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+                match Publication::parse(&line) {
+                    Some(publication) => self.publications.push_back(publication),
+                    None => return Ok(line),
+                }
+            }
+        };
+
+        if let Some(timeout_duration) = timeout {
+            tokio::time::timeout(timeout_duration, await_reply).await?
+        } else {
+            await_reply.await
+        }
+    }
+
+    /// Start receiving publications from topic, server has to run in pub/sub delivery mode
+    pub async fn subscribe(&mut self, timeout: Option<std::time::Duration>, topic: &str) -> Result<(), EchoClientError> {
+        let reply = self.pubsub_command(timeout, &format!("{SUBSCRIBE_COMMAND} {topic}")).await?;
+        if reply != format!("{SUBSCRIBED_NOTICE} {topic}") {
+            return Err(EchoClientError::BadResponse(reply));
+        }
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&mut self, timeout: Option<std::time::Duration>, topic: &str) -> Result<(), EchoClientError> {
+        let reply = self.pubsub_command(timeout, &format!("{UNSUBSCRIBE_COMMAND} {topic}")).await?;
+        if reply != format!("{UNSUBSCRIBED_NOTICE} {topic}") {
+            return Err(EchoClientError::BadResponse(reply));
+        }
+        Ok(())
+    }
+
+    /// Publish message to topic, returns number of subscribers it was delivered to
+    pub async fn publish(&mut self, timeout: Option<std::time::Duration>, topic: &str, message: &str) -> Result<usize, EchoClientError> {
+        let reply = self.pubsub_command(timeout, &format!("{PUBLISH_COMMAND} {topic} {message}")).await?;
+        reply.strip_prefix(&format!("{PUBLISHED_NOTICE} {topic} "))
+            .and_then(|subscribers| subscribers.parse().ok())
+            .ok_or(EchoClientError::BadResponse(reply))
+    }
+
+    /// Wait for next publication from subscribed topics, None means server closed connection
+    pub async fn next_publication(&mut self, timeout: Option<std::time::Duration>) -> Result<Option<Publication>, EchoClientError> {
+        if let Some(publication) = self.publications.pop_front() {
+            return Ok(Some(publication));
+        }
+
+        let await_publication = async {
+            let Some(line) = self.read_line(None).await? else {
+                return Ok(None);
+            };
+            Publication::parse(&line)
+                .map(Some)
+                .ok_or(EchoClientError::BadResponse(line))
+        };
+
+        if let Some(timeout_duration) = timeout {
+            tokio::time::timeout(timeout_duration, await_publication).await?
+        } else {
+            await_publication.await
+        }
+    }
+
+    /// Stream of publications from subscribed topics, ends when server closes connection
+    pub fn publications(&mut self) -> futures::stream::BoxStream<'_, Result<Publication, EchoClientError>> {
+        futures::stream::unfold(self, |client| async move {
+            client.next_publication(None).await
+                .transpose()
+                .map(|publication| (publication, client))
+        })
+        .boxed()
+    }
+
+    /// Wait for next message pushed by server out of band, None means server closed connection.
+    /// Replies arriving meanwhile are kept for later.
+    pub async fn next_pushed(&mut self, timeout: Option<std::time::Duration>) -> Result<Option<Bytes>, EchoClientError> {
+        if let Some(pushed) = self.pushed.pop_front() {
+            return Ok(Some(pushed));
+        }
+
+        let await_pushed = async {
+            loop {
+                let Some(frame) = self.read_raw_frame(false).await? else {
+                    return Ok(None);
+                };
+                if let Some(pushed) = parse_pushed(&frame) {
+                    return Ok(Some(pushed));
+                }
+                // Reply is kept for request waiting for it
+                match Publication::parse(&String::from_utf8_lossy(&frame)) {
+                    Some(publication) => self.publications.push_back(publication),
+                    None => self.replies.push_back(frame),
+                }
+            }
+        };
+
+        if let Some(timeout_duration) = timeout {
+            tokio::time::timeout(timeout_duration, await_pushed).await?
+        } else {
+            await_pushed.await
+        }
+    }
+
+    /// Stream of messages pushed by server, ends when server closes connection
+    pub fn pushed_messages(&mut self) -> futures::stream::BoxStream<'_, Result<Bytes, EchoClientError>> {
+        futures::stream::unfold(self, |client| async move {
+            client.next_pushed(None).await
+                .transpose()
+                .map(|pushed| (pushed, client))
+        })
+        .boxed()
+    }
+
+    /// Split into halves usable from different tasks, sender writes messages and receiver reads replies.
+    /// Client does not reconnect while split, lost connection is reported by receiver.
+    pub fn into_split(mut self) -> (EchoSender, EchoReceiver) {
+        let sender = EchoSender {
+            writer: self.writer.take().expect("client is not split"),
+            framing: self.framing,
+            span: self.span.clone(),
+            broken: self.broken,
+            sent: self.sent.clone(),
+        };
+        let receiver = EchoReceiver {
+            client: Arc::new(tokio::sync::Mutex::new(self)),
+            timeout: None,
+            pending: None,
+        };
+        (sender, receiver)
+    }
+
+    /// Read next reply as text line, messages pushed by server meanwhile are kept for later
+    async fn next_reply(&mut self, timeout: Option<std::time::Duration>) -> Result<Option<String>, EchoClientError> {
+        let awaited = self.sent.lock().unwrap().front().cloned();
+        let reply = if let Some(timeout_duration) = timeout {
+            tokio::time::timeout(timeout_duration, self.read_line(awaited.as_deref())).await??
+        } else {
+            self.read_line(awaited.as_deref()).await?
+        };
+        if reply.is_some() {
+            self.sent.lock().unwrap().pop_front();
+        }
+        Ok(reply)
+    }
+}
+
+/// Reading state of client borrowed apart from its writer, so replies can be read while writing
+struct ReadView<'a> {
+    reader: &'a mut FrameReader<ReadHalf<ClientStream>>,
+    pushed: &'a mut VecDeque<Bytes>,
+    replies: &'a mut VecDeque<Vec<u8>>,
+    framing: Framing,
+    /// Client has reconnect policy, closed connection is reported as error
+    eof_is_error: bool,
+    span: &'a tracing::Span,
+}
+
+impl ReadView<'_> {
+    /// Server announcing shutdown is reported as error, not as reply, unless it is echo of awaited message.
+    /// With reconnect policy closed connection is reported as error too, so client knows it should reconnect.
+    fn check_connection_lost(&self, frame: Option<Vec<u8>>, awaited: Option<&[u8]>) -> Result<Option<Vec<u8>>, EchoClientError> {
+        match frame {
+            None if self.eof_is_error => {
+                Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "server closed connection").into())
+            },
+            Some(frame) if !self.is_echo_of(&frame, awaited) && frame.strip_suffix(b"\n").unwrap_or(&frame) == SHUTDOWN_NOTICE.trim_end().as_bytes() => {
+                Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "server is shutting down").into())
+            },
+            frame => Ok(frame),
+        }
+    }
+
+    /// Read one frame including messages pushed by server, None means server closed connection
+    async fn read_raw_frame(&mut self, expect_text: bool) -> Result<Option<Vec<u8>>, EchoClientError> {
+        self.reader.set_expect_text(expect_text);
+        match self.reader.read_frame().await? {
+            Some(Frame::Complete(response)) => {
+                tracing::trace!(parent: self.span, length = response.len(), "Received frame");
+                Ok(Some(response))
+            },
+            Some(Frame::TooLong | Frame::Truncated(_)) => {
+                tracing::warn!(parent: self.span, "Received frame too long");
+                Err(EchoClientError::FrameTooLong)
+            },
+            Some(Frame::Mismatch) => {
+                tracing::warn!(parent: self.span, framing = ?self.framing, "Received frame does not match framing");
+                Err(EchoClientError::FramingMismatch(self.framing))
+            },
+            None => {
+                tracing::debug!(parent: self.span, "Server closed connection");
+                Ok(None)
+            },
+        }
+    }
+
+
+    /// Frame is echo of awaited message, server never alters echoed payloads,
+    /// so echo looking like pushed message or notice is told apart by what client waits for
+    fn is_echo_of(&self, frame: &[u8], awaited: Option<&[u8]>) -> bool {
+        awaited.is_some_and(|msg| match self.framing {
+            Framing::Line => frame.strip_suffix(b"\n").unwrap_or(frame) == msg,
+            _ => frame == msg,
+        })
+    }
+
+    /// Read one reply frame, messages pushed by server meanwhile are kept for later
+    async fn read_frame(&mut self, expect_text: bool, awaited: Option<&[u8]>) -> Result<Option<Vec<u8>>, EchoClientError> {
+        if let Some(reply) = self.replies.pop_front() {
+            return Ok(Some(reply));
+        }
+        loop {
+            let frame = self.read_raw_frame(expect_text).await?;
+            let Some(response) = self.check_connection_lost(frame, awaited)? else {
+                return Ok(None);
+            };
+            match parse_pushed(&response) {
+                Some(pushed) if !self.is_echo_of(&response, awaited) => self.pushed.push_back(pushed),
+                _ => return Ok(Some(response)),
+            }
+        }
+    }
+
+    /// Read one frame as text line without trailing newline
+    async fn read_line(&mut self, awaited: Option<&str>) -> Result<Option<String>, EchoClientError> {
+        let Some(response) = self.read_frame(true, awaited.map(str::as_bytes)).await? else {
+            return Ok(None);
+        };
+        let line = String::from_utf8_lossy(&response);
+        Ok(Some(line.strip_suffix('\n').unwrap_or(&line).to_string()))
+    }
+}
+
+/// Payload of message pushed by server, without prefix and trailing newline
+fn parse_pushed(frame: &[u8]) -> Option<Bytes> {
+    let payload = frame.strip_prefix(PUSH_PREFIX.as_bytes())?;
+    Some(Bytes::copy_from_slice(payload.strip_suffix(b"\n").unwrap_or(payload)))
+}
+
+fn encode_message(framing: Framing, msg: &[u8]) -> Vec<u8> {
+    match framing {
+        Framing::Line => framing.encode(&[msg, b"\n"].concat()),
+        _ => framing.encode(msg),
+    }
+}
+
+/// Writing half of split client
+pub struct EchoSender {
+    writer: WriteHalf<ClientStream>,
+    framing: Framing,
+    span: tracing::Span,
+    /// Previous send did not finish, part of its frame may be on the wire
+    broken: bool,
+    /// Shared with receiver, which takes reply equal to sent message for echo
+    sent: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl EchoSender {
+    /// Send message without waiting for reply, reply is delivered by receiver half.
+    /// Send which timed out, failed or was cancelled could leave partial frame behind, every later send fails then.
+    pub async fn send(&mut self, timeout: Option<std::time::Duration>, msg: &str) -> Result<(), EchoClientError> {
+        if self.broken {
+            return Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "previous send was interrupted").into());
+        }
+
+        let frame = encode_message(self.framing, msg.as_bytes());
+        tracing::trace!(parent: &self.span, length = frame.len(), "Sending frame");
+        self.broken = true;
+        // Recorded before writing, reply could be read before write returns
+        self.sent.lock().unwrap().push_back(msg.to_string());
+        let write = async {
+            self.writer.write_all(&frame).await?;
+            self.writer.flush().await
+        };
+
+        if let Some(timeout_duration) = timeout {
+            tokio::time::timeout(timeout_duration, write).await??;
+        } else {
+            write.await?;
+        }
+        self.broken = false;
+        Ok(())
+    }
+}
+
+/// Reading half of split client, stream of replies ending when server closes connection.
+/// Server announcing shutdown is yielded as ConnectionAborted error.
+pub struct EchoReceiver {
+    client: Arc<tokio::sync::Mutex<EchoClient>>,
+    timeout: Option<std::time::Duration>,
+    /// Reply being read, holds lock of client until it is done or dropped
+    pending: Option<BoxFuture<'static, Option<Result<String, EchoClientError>>>>,
+}
+
+impl EchoReceiver {
+    /// Limit waiting for each reply, elapsed timeout is yielded as error and stream goes on
+    pub fn with_timeout(mut self, timeout: Option<std::time::Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Join halves back into client, halves of different clients are handed back in error
+    pub fn reunite(mut self, sender: EchoSender) -> Result<EchoClient, ReuniteError> {
+        // Reading frames is cancel safe, part of reply read so far stays buffered
+        self.pending = None;
+        let is_pair = self.client.try_lock()
+            .is_ok_and(|client| client.reader.get_ref().is_pair_of(&sender.writer));
+        if !is_pair {
+            return Err(ReuniteError(sender, self));
+        }
+
+        let mut client = Arc::into_inner(self.client).expect("receiver owns client once reading stopped").into_inner();
+        client.writer = Some(sender.writer);
+        client.broken = sender.broken;
+        Ok(client)
+    }
+}
+
+impl futures::Stream for EchoReceiver {
+    type Item = Result<String, EchoClientError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let pending = this.pending.get_or_insert_with(|| {
+            let client = this.client.clone();
+            let timeout = this.timeout;
+            async move {
+                client.lock_owned().await.next_reply(timeout).await.transpose()
+            }
+            .boxed()
+        });
+
+        let reply = ready!(pending.as_mut().poll(cx));
+        this.pending = None;
+        Poll::Ready(reply)
+    }
+}
+
+/// Halves passed to reunite come from different clients
+#[derive(thiserror::Error)]
+#[error("ReuniteError, halves are not from the same client")]
+pub struct ReuniteError(pub EchoSender, pub EchoReceiver);
+
+impl std::fmt::Debug for ReuniteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ReuniteError(..)")
+    }
+}
@@ -0,0 +1,2389 @@
+/// Line sent to every connected client when server starts shutting down
+pub const SHUTDOWN_NOTICE: &str = "SERVER_SHUTDOWN\n";
+
+/// Line sent to client rejected because of connection limit
+pub const SERVER_BUSY_NOTICE: &str = "SERVER_BUSY\n";
+
+/// Line sent to client before disconnecting it for exceeding maximum frame length
+pub const FRAME_TOO_LONG_NOTICE: &str = "FRAME_TOO_LONG\n";
+
+/// Line sent to client before disconnecting it for using different framing than server
+pub const FRAMING_MISMATCH_NOTICE: &str = "FRAMING_MISMATCH\n";
+
+/// Line sent to client before disconnecting it because idle, read or lifetime timeout expired
+pub const TIMEOUT_NOTICE: &str = "TIMEOUT\n";
+
+/// Line sent to client instead of reply to message rejected because of rate limit
+pub const RATE_LIMITED_NOTICE: &str = "RATE_LIMITED\n";
+
+/// Prefix of line sent to other clients in broadcast mode when client connects, followed by connection id and address
+pub const JOIN_NOTICE: &str = "JOINED";
+
+/// Prefix of line sent to other clients in broadcast mode when client disconnects, followed by connection id and address
+pub const LEAVE_NOTICE: &str = "LEFT";
+
+/// Prefix of message pushed by server out of band, lets clients tell it apart from echo replies.
+/// Echoed payloads are never altered, client waiting for echo of message with this prefix takes it for reply.
+pub const PUSH_PREFIX: &str = "PUSH ";
+
+/// Line starting with this prefix followed by transform name switches transform used for connection
+pub const TRANSFORM_COMMAND: &str = "TRANSFORM ";
+
+/// Line sent to client after switching transform
+pub const TRANSFORM_OK_NOTICE: &str = "TRANSFORM_OK\n";
+
+/// Line sent to client which asked for transform missing in registry
+pub const TRANSFORM_UNKNOWN_NOTICE: &str = "TRANSFORM_UNKNOWN\n";
+
+use std::{path::Path, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex, RwLock}, time::Duration};
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::Instrument;
+
+use crate::{
+    access::AccessRules,
+    events::{event_stream, DisconnectReason, RejectReason, ServerEvent, EVENTS_CAPACITY},
+    framing::{Frame, FrameOverflowPolicy, FrameReader, Framing},
+    hook::{run_hooks, HookAction, MessageHook},
+    metrics::{serve_metrics, MetricsSnapshot, ServerMetrics},
+    pubsub::execute_command,
+    rate_limit::{ConnectionLimiters, IpRateLimiters, RateLimit, RateLimitPolicy, RateLimiter},
+    registry::{ConnectionInfo, ConnectionRegistry, ConnectionStats, CountedStream},
+    transform::{apply_transform, TransformFn, TransformRegistry, ECHO_TRANSFORM},
+    tls::TlsConfigError,
+    transport::{Listener, PeerAddr, Transport},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum EchoServerError {
+    #[error("IoError, reason='{0}'")]
+    IoError(#[from] tokio::io::Error),
+    
+    #[error("TaskJoinError, reason='{0}'")]
+    TaskJoinError(#[from] tokio::task::JoinError),
+
+    #[error("TlsConfigError, reason='{0}'")]
+    TlsConfigError(#[from] TlsConfigError),
+
+    #[error("FrameTooLong, client='{client}', max_frame_length={max_frame_length}")]
+    FrameTooLong {
+        client: String,
+        max_frame_length: usize,
+    },
+
+    #[error("FramingMismatch, client='{client}', expected={framing:?}")]
+    FramingMismatch {
+        client: String,
+        framing: Framing,
+    },
+
+    #[error("QueueOverflow, client='{client}'")]
+    QueueOverflow {
+        client: String,
+    },
+
+    #[error("UnknownTransform, name='{0}'")]
+    UnknownTransform(String),
+
+    #[error("UnknownConnection, id={0}")]
+    UnknownConnection(u64),
+
+    #[error("InvalidRateLimit, limit={0:?}")]
+    InvalidRateLimit(RateLimit),
+
+    #[error("KillFailed")]
+    KillFailed,
+}
+
+pub struct EchoServer {
+    listener: Listener,
+    queue_capacity: usize,
+    queue_overflow_policy: QueueOverflowPolicy,
+    msg_handlers: Vec<Box<dyn MessageHook>>,
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    shutdown_grace_period: Duration,
+    connection_limit: Option<(usize, AdmissionPolicy)>,
+    max_frame_length: usize,
+    frame_overflow_policy: FrameOverflowPolicy,
+    message_mode: MessageMode,
+    framing: Framing,
+    transforms: TransformRegistry,
+    transform: String,
+    delivery_mode: DeliveryMode,
+    events_tx: tokio::sync::broadcast::Sender<ServerEvent>,
+    metrics_listener: Option<tokio::net::TcpListener>,
+    timeouts: ConnectionTimeouts,
+    connection_rate_limit: Option<RateLimit>,
+    ip_rate_limit: Option<RateLimit>,
+    rate_limit_policy: RateLimitPolicy,
+    access_rules: AccessRules,
+}
+
+/// Message received from client together with information who sent it and when
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EchoMessage {
+    /// Unique id assigned to connection when it was accepted
+    pub connection_id: u64,
+    pub peer_addr: PeerAddr,
+    pub received_at: std::time::SystemTime,
+    /// Position of message within its connection, starting from 0
+    pub sequence: u64,
+    pub payload: Bytes,
+}
+
+/// How payloads are interpreted by server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageMode {
+    /// Messages must be valid UTF-8, client sending anything else gets disconnected
+    Text,
+    /// Arbitrary bytes are echoed unchanged
+    Binary,
+}
+
+/// What to do with message when incoming messages queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueOverflowPolicy {
+    /// Stop reading from client until there is room in queue
+    Block,
+    /// Discard message which just arrived
+    DropNewest,
+    /// Discard oldest queued message to make room for new one
+    DropOldest,
+    /// Discard message and disconnect client
+    Disconnect,
+}
+
+/// Who receives reply to message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Reply goes back to sender only
+    Echo,
+    /// Reply goes to every other connected client, optionally also to sender.
+    /// Clients are notified when others join or leave.
+    Broadcast {
+        include_sender: bool,
+    },
+    /// Messages are pub/sub commands, publications go only to subscribers of topic
+    PubSub,
+}
+
+/// What to do with clients connecting above maximum concurrent connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdmissionPolicy {
+    /// Send SERVER_BUSY_NOTICE and disconnect
+    Reject,
+    /// Keep connection waiting until one of the slots is released
+    Queue,
+    /// Disconnect without any message
+    Close,
+}
+
+/// Limits on how long connection may stay open, None means unlimited
+#[derive(Debug, Clone, Copy, Default)]
+struct ConnectionTimeouts {
+    idle: Option<Duration>,
+    read: Option<Duration>,
+    max_lifetime: Option<Duration>,
+}
+
+impl ConnectionTimeouts {
+    /// Client stalling TLS handshake is not sending messages, shorter of idle and read timeout limits it
+    fn handshake(&self) -> Option<Duration> {
+        [self.idle, self.read].into_iter().flatten().min()
+    }
+}
+
+/// Finish TLS handshake within given time
+async fn accept_tls(
+    acceptor: &tokio_rustls::TlsAcceptor,
+    socket: Transport,
+    timeout: Option<Duration>,
+) -> std::io::Result<tokio_rustls::server::TlsStream<Transport>> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, acceptor.accept(socket)).await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "TLS handshake timed out"))?,
+        None => acceptor.accept(socket).await,
+    }
+}
+
+/// State shared by all connection tasks
+#[derive(Clone)]
+struct ConnectionContext {
+    queue: MessageQueue,
+    metrics: Arc<ServerMetrics>,
+    msg_handlers: Arc<Vec<Box<dyn MessageHook>>>,
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    stop_rx: tokio::sync::watch::Receiver<bool>,
+    connections_count: Arc<AtomicUsize>,
+    max_frame_length: usize,
+    frame_overflow_policy: FrameOverflowPolicy,
+    message_mode: MessageMode,
+    framing: Framing,
+    transforms: Arc<TransformRegistry>,
+    default_transform: Arc<TransformFn>,
+    delivery_mode: DeliveryMode,
+    registry: ConnectionRegistry,
+    events_tx: tokio::sync::broadcast::Sender<ServerEvent>,
+    timeouts: ConnectionTimeouts,
+    connection_rate_limit: Option<RateLimit>,
+    ip_rate_limiters: Option<Arc<IpRateLimiters>>,
+    rate_limit_policy: RateLimitPolicy,
+}
+
+impl ConnectionContext {
+    fn emit(&self, event: ServerEvent) {
+        // Nobody listening is fine
+        let _ = self.events_tx.send(event);
+    }
+}
+
+/// Whether connection task served client, only served connections are counted in shutdown report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionTask {
+    Served,
+    /// Client was rejected or server stopped while connection waited for admission
+    NotServed,
+}
+
+/// Keeps connections count up to date, also when connection task gets aborted
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl ConnectionGuard {
+    fn new(connections_count: Arc<AtomicUsize>) -> Self {
+        connections_count.fetch_add(1, Ordering::Relaxed);
+        Self(connections_count)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn send_notice<W: AsyncWrite + Unpin>(writer: &mut W, framing: Framing, notice: &str) -> std::io::Result<()> {
+    writer.write_all(&framing.encode_line(notice)).await?;
+    writer.shutdown().await
+}
+
+/// Reason to close connection which comes while server waits for client
+enum Interruption {
+    IdleTimeout,
+    LifetimeExceeded,
+    Kicked(Option<String>),
+    ServerShutdown,
+}
+
+/// Wait for interruption which ends connection even while it is busy with message
+async fn interrupted(
+    lifetime_deadline: Option<tokio::time::Instant>,
+    kick_rx: &mut tokio::sync::oneshot::Receiver<Option<String>>,
+    stop_rx: &mut tokio::sync::watch::Receiver<bool>,
+) -> Interruption {
+    tokio::select! {
+        _ = sleep_until(lifetime_deadline) => Interruption::LifetimeExceeded,
+        Ok(farewell) = kick_rx => Interruption::Kicked(farewell),
+        _ = stop_rx.changed() => Interruption::ServerShutdown,
+    }
+}
+
+/// Tell client why server closes connection
+async fn close_interrupted<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    framing: Framing,
+    stats: &ConnectionStats,
+    interruption: Interruption,
+) -> DisconnectReason {
+    let (notice, reason) = match interruption {
+        Interruption::IdleTimeout => {
+            tracing::debug!("Client idle for too long");
+            (Some(TIMEOUT_NOTICE.to_string()), DisconnectReason::IdleTimeout)
+        },
+        Interruption::LifetimeExceeded => {
+            tracing::debug!("Connection exceeded maximum lifetime");
+            (Some(TIMEOUT_NOTICE.to_string()), DisconnectReason::LifetimeExceeded)
+        },
+        Interruption::Kicked(farewell) => {
+            tracing::info!(farewell = ?farewell, "Kicking client");
+            let farewell = farewell.map(|farewell| if farewell.ends_with('\n') { farewell } else { farewell + "\n" });
+            (farewell, DisconnectReason::Kicked)
+        },
+        Interruption::ServerShutdown => {
+            tracing::debug!("Notifying client about shutdown");
+            (Some(SHUTDOWN_NOTICE.to_string()), DisconnectReason::ServerShutdown)
+        },
+    };
+
+    if let Some(notice) = notice {
+        match send_notice(writer, framing, &notice).await {
+            Ok(()) if reason == DisconnectReason::Kicked => stats.message_sent(),
+            Ok(()) => {},
+            Err(e) => tracing::warn!(error = %e, "Couldnt notify client"),
+        }
+    }
+    reason
+}
+
+/// Sleep until deadline, without deadline never completes
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Name of transform requested by client, None if payload is a regular message
+fn parse_transform_command(payload: &[u8]) -> Option<&str> {
+    let line = std::str::from_utf8(payload).ok()?;
+    line.strip_suffix('\n').unwrap_or(line).strip_prefix(TRANSFORM_COMMAND)
+}
+
+/// Incoming messages queue shared by TCP and UDP servers
+#[derive(Clone)]
+pub(crate) struct MessageQueue {
+    msg_tx: tokio::sync::mpsc::Sender<EchoMessage>,
+    msg_rx: Arc<tokio::sync::Mutex<tokio::sync::mpsc::Receiver<EchoMessage>>>,
+    policy: QueueOverflowPolicy,
+}
+
+/// Queue was full when message arrived, overflow policy was applied
+pub(crate) struct QueueOverflow {
+    /// Number of messages discarded, new one or oldest ones
+    pub dropped: u64,
+}
+
+impl MessageQueue {
+    pub fn new(capacity: usize, policy: QueueOverflowPolicy) -> Self {
+        let (msg_tx, msg_rx) = tokio::sync::mpsc::channel(capacity);
+        Self {
+            msg_tx,
+            msg_rx: Arc::new(tokio::sync::Mutex::new(msg_rx)),
+            policy,
+        }
+    }
+
+    pub fn policy(&self) -> QueueOverflowPolicy {
+        self.policy
+    }
+
+    /// Receiving side for server handler, holding it does not keep queue open
+    pub fn receiver(&self) -> Arc<tokio::sync::Mutex<tokio::sync::mpsc::Receiver<EchoMessage>>> {
+        self.msg_rx.clone()
+    }
+
+    /// Put message into queue respecting overflow policy
+    pub async fn push(&self, message: EchoMessage) -> Result<(), QueueOverflow> {
+        use tokio::sync::mpsc::error::TrySendError;
+
+        if self.policy == QueueOverflowPolicy::Block {
+            if self.msg_tx.send(message).await.is_err() {
+                tracing::warn!("Couldnt queue message, queue closed");
+            }
+            return Ok(());
+        }
+
+        let message = match self.msg_tx.try_send(message) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Closed(_)) => {
+                tracing::warn!("Couldnt queue message, queue closed");
+                return Ok(());
+            },
+            Err(TrySendError::Full(message)) => message,
+        };
+
+        if self.policy != QueueOverflowPolicy::DropOldest {
+            return Err(QueueOverflow { dropped: 1 });
+        }
+
+        // Holding receiver makes sure nobody else takes the freed slot
+        let mut msg_rx = self.msg_rx.lock().await;
+        let mut message = message;
+        let mut dropped = 0;
+        loop {
+            if msg_rx.try_recv().is_ok() {
+                dropped += 1;
+            }
+            match self.msg_tx.try_send(message) {
+                Err(TrySendError::Full(rejected)) => message = rejected,
+                _ => break,
+            }
+        }
+        Err(QueueOverflow { dropped })
+    }
+}
+
+/// Put message into incoming messages queue, fails when client should be disconnected because of overflow
+async fn queue_message(context: &ConnectionContext, message: EchoMessage) -> Result<(), EchoServerError> {
+    let client_addr = message.peer_addr.clone();
+    let message_connection_id = message.connection_id;
+    let Err(overflow) = context.queue.push(message).await else {
+        return Ok(());
+    };
+
+    let policy = context.queue.policy();
+    context.metrics.queue_drops.fetch_add(overflow.dropped, Ordering::Relaxed);
+    context.emit(ServerEvent::QueueOverflow { connection_id: message_connection_id, policy });
+    match policy {
+        QueueOverflowPolicy::DropOldest => {
+            tracing::debug!(?policy, "Queue full, dropped oldest message to make room");
+            Ok(())
+        },
+        QueueOverflowPolicy::Disconnect => {
+            tracing::debug!(?policy, "Queue full, disconnecting client");
+            Err(EchoServerError::QueueOverflow { client: client_addr.to_string() })
+        },
+        _ => {
+            tracing::debug!(?policy, "Queue full, dropped message");
+            Ok(())
+        },
+    }
+}
+
+/// Summary of connections which were open when server was shut down
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub closed_cleanly: usize,
+    pub aborted: usize,
+}
+
+pub struct EchoServerHandler {
+    shutdown_tx: tokio::sync::oneshot::Sender<()>,
+    task_handler: tokio::task::JoinHandle<ShutdownReport>,
+    msg_rx: Arc<tokio::sync::Mutex<tokio::sync::mpsc::Receiver<EchoMessage>>>, // no longer shutdown at drop
+    metrics: Arc<ServerMetrics>,
+    connections_count: Arc<AtomicUsize>,
+    registry: ConnectionRegistry,
+    framing: Framing,
+    events_tx: tokio::sync::broadcast::Sender<ServerEvent>,
+    access_rules: Arc<RwLock<AccessRules>>,
+}
+
+impl EchoServer {
+    /// Bind listener to address ready to be started
+    pub async fn bind<A: tokio::net::ToSocketAddrs>(addr: A) -> Result<Self, EchoServerError> {
+        Ok(Self::with_bound_listener(Listener::Tcp(tokio::net::TcpListener::bind(addr).await?)))
+    }
+
+    /// Bind Unix domain socket at path, socket file left by crashed server is removed first.
+    /// Socket file is removed again when server stops.
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(path: P) -> Result<Self, EchoServerError> {
+        Ok(Self::with_bound_listener(Listener::bind_unix(path.as_ref(), None)?))
+    }
+
+    /// Bind Unix domain socket like `bind_unix` with access mode of socket file, e.g. 0o600 to allow only owner to connect.
+    /// Mode is set before socket is reachable at path, so no client can connect in between.
+    #[cfg(unix)]
+    pub fn bind_unix_with_permissions<P: AsRef<Path>>(path: P, mode: u32) -> Result<Self, EchoServerError> {
+        Ok(Self::with_bound_listener(Listener::bind_unix(path.as_ref(), Some(mode))?))
+    }
+
+    fn with_bound_listener(listener: Listener) -> Self {
+        Self {
+            listener,
+            queue_capacity: 32,
+            queue_overflow_policy: QueueOverflowPolicy::DropNewest,
+            msg_handlers: Vec::new(),
+            tls_acceptor: None,
+            shutdown_grace_period: Duration::from_secs(5),
+            connection_limit: None,
+            max_frame_length: crate::framing::DEFAULT_MAX_FRAME_LENGTH,
+            frame_overflow_policy: FrameOverflowPolicy::Disconnect,
+            message_mode: MessageMode::Text,
+            framing: Framing::Line,
+            transforms: TransformRegistry::default(),
+            transform: ECHO_TRANSFORM.to_string(),
+            delivery_mode: DeliveryMode::Echo,
+            events_tx: tokio::sync::broadcast::channel(EVENTS_CAPACITY).0,
+            metrics_listener: None,
+            timeouts: ConnectionTimeouts::default(),
+            connection_rate_limit: None,
+            ip_rate_limit: None,
+            rate_limit_policy: RateLimitPolicy::Delay,
+            access_rules: AccessRules::default(),
+        }
+    }
+
+    /// Append hook to chain called for every message, closure can be used to only observe messages
+    pub fn with_listener<H: MessageHook>(mut self, msg_handler: H) -> Self {
+        self.msg_handlers.push(Box::new(msg_handler));
+        self
+    }
+
+    /// Accept only TLS connections, certificate chain and private key are read from PEM files
+    pub fn with_tls<P: AsRef<Path>>(mut self, cert_path: P, key_path: P) -> Result<Self, EchoServerError> {
+        self.tls_acceptor = Some(crate::tls::make_acceptor(cert_path, key_path)?);
+        Ok(self)
+    }
+
+    /// Time given to open connections to finish after shutdown, remaining ones get aborted
+    pub fn with_shutdown_grace_period(mut self, grace_period: Duration) -> Self {
+        self.shutdown_grace_period = grace_period;
+        self
+    }
+
+    /// Size of incoming messages queue, policy decides what happens to messages when it is full
+    pub fn with_queue_capacity(mut self, queue_capacity: usize, policy: QueueOverflowPolicy) -> Self {
+        self.queue_capacity = queue_capacity;
+        self.queue_overflow_policy = policy;
+        self
+    }
+
+    /// Limit number of concurrently served clients, policy decides what happens to the rest
+    pub fn with_max_connections(mut self, max_connections: usize, policy: AdmissionPolicy) -> Self {
+        self.connection_limit = Some((max_connections, policy));
+        self
+    }
+
+    /// Limit length of single message (without newline), policy decides what happens to longer ones
+    pub fn with_max_frame_length(mut self, max_frame_length: usize, policy: FrameOverflowPolicy) -> Self {
+        self.max_frame_length = max_frame_length;
+        self.frame_overflow_policy = policy;
+        self
+    }
+
+    /// Select whether messages are validated as UTF-8 text or treated as raw bytes
+    pub fn with_message_mode(mut self, message_mode: MessageMode) -> Self {
+        self.message_mode = message_mode;
+        self
+    }
+
+    /// Select how messages are delimited, clients have to use the same framing
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Select whether replies are echoed to sender or broadcast to all clients
+    pub fn with_delivery_mode(mut self, delivery_mode: DeliveryMode) -> Self {
+        self.delivery_mode = delivery_mode;
+        self
+    }
+
+    /// Replace registry of transforms available to server and its clients
+    pub fn with_transforms(mut self, transforms: TransformRegistry) -> Self {
+        self.transforms = transforms;
+        self
+    }
+
+    /// Transform applied to replies until client selects another one, it has to be in registry
+    pub fn with_transform(mut self, name: &str) -> Self {
+        self.transform = name.to_string();
+        self
+    }
+
+    /// Disconnect client which did not send any message for given time.
+    /// TLS handshake has to finish within shorter of idle and read timeout.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.timeouts.idle = Some(idle_timeout);
+        self
+    }
+
+    /// Disconnect client which started sending message but did not send any more of it for given time.
+    /// TLS handshake has to finish within shorter of idle and read timeout.
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.timeouts.read = Some(read_timeout);
+        self
+    }
+
+    /// Disconnect every client after given time regardless of its activity
+    pub fn with_max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.timeouts.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    /// Limit rate of messages of every connection, rates have to be greater than zero
+    pub fn with_rate_limit(mut self, limit: RateLimit) -> Result<Self, EchoServerError> {
+        if !limit.is_valid() {
+            return Err(EchoServerError::InvalidRateLimit(limit));
+        }
+        self.connection_rate_limit = Some(limit);
+        Ok(self)
+    }
+
+    /// Limit rate of messages of all connections from the same IP together, rates have to be greater than zero.
+    /// Clients connected over Unix domain socket are not limited by it.
+    pub fn with_ip_rate_limit(mut self, limit: RateLimit) -> Result<Self, EchoServerError> {
+        if !limit.is_valid() {
+            return Err(EchoServerError::InvalidRateLimit(limit));
+        }
+        self.ip_rate_limit = Some(limit);
+        Ok(self)
+    }
+
+    /// Select whether messages exceeding rate limits are delayed (default) or rejected
+    pub fn with_rate_limit_policy(mut self, policy: RateLimitPolicy) -> Self {
+        self.rate_limit_policy = policy;
+        self
+    }
+
+    /// Allow or deny peers by IP address, rules can be replaced later through handler
+    pub fn with_access_rules(mut self, access_rules: AccessRules) -> Self {
+        self.access_rules = access_rules;
+        self
+    }
+
+    /// Serve metrics in Prometheus text format at GET /metrics on given address while server runs
+    pub async fn with_metrics_endpoint<A: tokio::net::ToSocketAddrs>(mut self, addr: A) -> Result<Self, EchoServerError> {
+        self.metrics_listener = Some(tokio::net::TcpListener::bind(addr).await?);
+        Ok(self)
+    }
+
+    /// Address of metrics endpoint, None if it was not enabled
+    pub fn get_metrics_address(&self) -> Option<std::net::SocketAddr> {
+        self.metrics_listener.as_ref().and_then(|listener| listener.local_addr().ok())
+    }
+
+    pub async fn bind_any_local() -> Result<Self, EchoServerError> {
+        Self::bind("127.0.0.1:0").await
+    }
+
+    pub fn get_local_address(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Subscribe to lifecycle events before server is started, so Started event is not missed
+    pub fn subscribe_events(&self) -> futures::stream::BoxStream<'static, ServerEvent> {
+        event_stream(self.events_tx.subscribe())
+    }
+
+    /// Path of Unix domain socket, None for TCP server
+    #[cfg(unix)]
+    pub fn get_local_path(&self) -> Option<&Path> {
+        self.listener.local_path()
+    }
+
+    /// Run listening in background
+    #[must_use = "EchoServerHandler must be stored to keep the server alive"]
+    pub fn run(mut self) -> Result<EchoServerHandler, EchoServerError> {
+        /// Helper function to process messages in connections
+        async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+            stream: S,
+            client_addr: PeerAddr,
+            connection_id: u64,
+            mut context: ConnectionContext,
+        ) -> Result<DisconnectReason, EchoServerError> {
+            tracing::info!("Incomming connection");
+            context.emit(ServerEvent::ClientConnected { connection_id, peer_addr: client_addr.clone() });
+
+            let leave_notice = match context.delivery_mode {
+                DeliveryMode::Echo | DeliveryMode::PubSub => None,
+                DeliveryMode::Broadcast { .. } => {
+                    let join_notice = format!("{JOIN_NOTICE} {connection_id} {client_addr}\n");
+                    context.registry.broadcast(Bytes::from(context.framing.encode_line(&join_notice)), None);
+                    Some(Bytes::from(context.framing.encode_line(&format!("{LEAVE_NOTICE} {connection_id} {client_addr}\n"))))
+                },
+            };
+            let mut registration = context.registry.register(connection_id, client_addr.clone(), leave_notice);
+            let stats = registration.stats.clone();
+            let limiters = ConnectionLimiters {
+                connection: context.connection_rate_limit.map(|limit| Arc::new(Mutex::new(RateLimiter::new(limit)))),
+                ip: match (&context.ip_rate_limiters, &client_addr) {
+                    (Some(ip_rate_limiters), PeerAddr::Tcp(address)) => Some(ip_rate_limiters.get(address.ip())),
+                    _ => None,
+                },
+            };
+            context.registry.set_limiters(connection_id, limiters.clone());
+            let stream = CountedStream::new(CountedStream::new(stream, stats.clone()), context.metrics.traffic.clone());
+            let (reader, mut writer) = tokio::io::split(stream);
+
+            let mut read_buffer = FrameReader::new(reader, context.framing, Some(context.max_frame_length), context.frame_overflow_policy)
+                .with_read_timeout(context.timeouts.read);
+            if context.message_mode == MessageMode::Text {
+                read_buffer = read_buffer.expect_text();
+            }
+
+            let mut sequence = 0;
+            let mut transform = context.default_transform.clone();
+            let connected_at = tokio::time::Instant::now();
+            let lifetime_deadline = context.timeouts.max_lifetime.map(|max_lifetime| connected_at + max_lifetime);
+            let mut idle_deadline = context.timeouts.idle.map(|idle_timeout| connected_at + idle_timeout);
+            let reason = 'connection: loop {
+                let read_result = tokio::select! {
+                    result = read_buffer.read_frame() => result,
+                    _ = sleep_until(idle_deadline) => {
+                        break close_interrupted(&mut writer, context.framing, &stats, Interruption::IdleTimeout).await;
+                    },
+                    _ = sleep_until(lifetime_deadline) => {
+                        break close_interrupted(&mut writer, context.framing, &stats, Interruption::LifetimeExceeded).await;
+                    },
+                    Some(frame) = registration.outbound_rx.recv() => {
+                        // Frames from other connections are written between own messages
+                        writer.write_all(&frame).await?;
+                        writer.flush().await?;
+                        stats.message_sent();
+                        continue;
+                    },
+                    Ok(farewell) = &mut registration.kick_rx => {
+                        break close_interrupted(&mut writer, context.framing, &stats, Interruption::Kicked(farewell)).await;
+                    },
+                    _ = context.stop_rx.changed() => {
+                        // Message being processed is always finished, only waiting for next one is interrupted
+                        break close_interrupted(&mut writer, context.framing, &stats, Interruption::ServerShutdown).await;
+                    },
+                };
+
+                idle_deadline = context.timeouts.idle.map(|idle_timeout| tokio::time::Instant::now() + idle_timeout);
+                let payload = match read_result {
+                    Ok(None) => {
+                        tracing::debug!("Client closed connection");
+                        break DisconnectReason::ClientClosed;
+                    },
+                    Ok(Some(Frame::TooLong)) => {
+                        if let Err(e) = send_notice(&mut writer, context.framing, FRAME_TOO_LONG_NOTICE).await {
+                            tracing::warn!(error = %e, "Couldnt notify client");
+                        }
+                        return Err(EchoServerError::FrameTooLong {
+                            client: client_addr.to_string(),
+                            max_frame_length: context.max_frame_length,
+                        });
+                    },
+                    Ok(Some(Frame::Mismatch)) => {
+                        if let Err(e) = send_notice(&mut writer, context.framing, FRAMING_MISMATCH_NOTICE).await {
+                            tracing::warn!(error = %e, "Couldnt notify client");
+                        }
+                        return Err(EchoServerError::FramingMismatch {
+                            client: client_addr.to_string(),
+                            framing: context.framing,
+                        });
+                    },
+                    Ok(Some(Frame::Truncated(payload))) => {
+                        tracing::debug!(max_frame_length = context.max_frame_length, "Message truncated");
+                        match context.message_mode {
+                            // Multibyte character could be cut in half
+                            MessageMode::Text => Bytes::from(String::from_utf8_lossy(&payload).into_owned()),
+                            MessageMode::Binary => Bytes::from(payload),
+                        }
+                    },
+                    Ok(Some(Frame::Complete(payload))) => match context.message_mode {
+                        MessageMode::Text => match String::from_utf8(payload) {
+                            Ok(line) => Bytes::from(line),
+                            Err(e) => {
+                                tracing::warn!(error = %e, "Reading message failed");
+                                context.emit(ServerEvent::ReadError { connection_id, error: e.to_string() });
+                                break DisconnectReason::ReadFailed;
+                            },
+                        },
+                        MessageMode::Binary => Bytes::from(payload),
+                    },
+                    Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                        tracing::debug!("Message not completed within read timeout");
+                        if let Err(e) = send_notice(&mut writer, context.framing, TIMEOUT_NOTICE).await {
+                            tracing::warn!(error = %e, "Couldnt notify client");
+                        }
+                        break DisconnectReason::ReadTimeout;
+                    },
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Reading message failed");
+                        context.emit(ServerEvent::ReadError { connection_id, error: e.to_string() });
+                        break DisconnectReason::ReadFailed;
+                    }
+                };
+
+                stats.message_received();
+
+                if let Err(wait_time) = limiters.try_acquire(payload.len()) {
+                    stats.message_rate_limited();
+                    match context.rate_limit_policy {
+                        RateLimitPolicy::Reject => {
+                            tracing::debug!("Message rejected by rate limit");
+                            writer.write_all(&context.framing.encode_line(RATE_LIMITED_NOTICE)).await?;
+                            writer.flush().await?;
+                            stats.message_sent();
+                            continue;
+                        },
+                        RateLimitPolicy::Delay => {
+                            tracing::debug!(?wait_time, "Message delayed by rate limit");
+                            let mut wait_time = wait_time;
+                            // Tokens shared with other connections could be taken meanwhile
+                            loop {
+                                let interruption = tokio::select! {
+                                    _ = tokio::time::sleep(wait_time) => None,
+                                    interruption = interrupted(lifetime_deadline, &mut registration.kick_rx, &mut context.stop_rx) => Some(interruption),
+                                };
+                                if let Some(interruption) = interruption {
+                                    break 'connection close_interrupted(&mut writer, context.framing, &stats, interruption).await;
+                                }
+                                match limiters.try_acquire(payload.len()) {
+                                    Ok(()) => break,
+                                    Err(next_wait_time) => wait_time = next_wait_time,
+                                }
+                            }
+                        },
+                    }
+                }
+
+                let handling_started = std::time::Instant::now();
+
+                if let Some(name) = parse_transform_command(&payload) {
+                    let notice = match context.transforms.get(name) {
+                        Some(selected) => {
+                            tracing::debug!(transform = name, "Client switched transform");
+                            transform = selected;
+                            TRANSFORM_OK_NOTICE
+                        },
+                        None => TRANSFORM_UNKNOWN_NOTICE,
+                    };
+                    writer.write_all(&context.framing.encode_line(notice)).await?;
+                    writer.flush().await?;
+                    stats.message_sent();
+                    continue;
+                }
+
+                let message = EchoMessage {
+                    connection_id,
+                    peer_addr: client_addr.clone(),
+                    received_at: std::time::SystemTime::now(),
+                    sequence,
+                    payload,
+                };
+                sequence += 1;
+
+                // Full queue blocks connection with Block policy, it still has to be possible to close it
+                let mut stop_rx = context.stop_rx.clone();
+                tokio::select! {
+                    queued = queue_message(&context, message.clone()) => queued?,
+                    interruption = interrupted(lifetime_deadline, &mut registration.kick_rx, &mut stop_rx) => {
+                        break 'connection close_interrupted(&mut writer, context.framing, &stats, interruption).await;
+                    },
+                }
+
+                let reply = apply_transform(transform.as_ref(), &message.payload);
+                let reply = match run_hooks(&context.msg_handlers, &message, reply).await {
+                    HookAction::Reply(reply) => Some(reply),
+                    HookAction::Suppress => None,
+                    HookAction::Close => {
+                        tracing::debug!("Hook closed connection");
+                        break DisconnectReason::ClosedByHook;
+                    },
+                };
+
+                let frame = match (reply, context.delivery_mode) {
+                    (None, _) => None,
+                    (Some(reply), DeliveryMode::Echo) => Some(Bytes::from(context.framing.encode(&reply))),
+                    (Some(reply), DeliveryMode::Broadcast { include_sender }) => {
+                        let frame = Bytes::from(context.framing.encode(&reply));
+                        context.registry.broadcast(frame.clone(), Some(connection_id));
+                        include_sender.then_some(frame)
+                    },
+                    (Some(_), DeliveryMode::PubSub) => {
+                        // Commands come from client as sent, transforms and hooks must not reroute them
+                        let response = execute_command(&context.registry, connection_id, context.framing, &message.payload);
+                        Some(Bytes::from(context.framing.encode_line(&response)))
+                    },
+                };
+
+                if let Some(frame) = frame {
+                    writer.write_all(&frame).await?;
+                    writer.flush().await?;
+                    stats.message_sent();
+                }
+                // Messages broadcast only to others or suppressed by hook are handled too
+                context.metrics.messages_echoed.fetch_add(1, Ordering::Relaxed);
+                context.metrics.handling_latency.observe(handling_started.elapsed());
+            };
+
+            Ok(reason)
+        }
+
+        /// Helper function to finish TLS handshake if required and serve admitted connection
+        async fn serve_connection(
+            socket: Transport,
+            client_addr: PeerAddr,
+            connection_id: u64,
+            permit: Option<tokio::sync::OwnedSemaphorePermit>,
+            context: ConnectionContext,
+        ) -> ConnectionTask {
+            let _permit = permit;
+            let _guard = ConnectionGuard::new(context.connections_count.clone());
+            context.metrics.connections_accepted.fetch_add(1, Ordering::Relaxed);
+
+            let result = match context.tls_acceptor.clone() {
+                Some(acceptor) => match accept_tls(&acceptor, socket, context.timeouts.handshake()).await {
+                    Ok(tls_stream) => handle_connection(tls_stream, client_addr.clone(), connection_id, context.clone()).await,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "TLS handshake failed");
+                        context.emit(ServerEvent::AcceptError { error: format!("TLS handshake with {client_addr} failed, reason {e}") });
+                        return ConnectionTask::Served;
+                    },
+                },
+                None => handle_connection(socket, client_addr.clone(), connection_id, context.clone()).await,
+            };
+
+            let reason = match result {
+                Ok(reason) => reason,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Connection closed with error");
+                    match e {
+                        EchoServerError::FrameTooLong { .. } => DisconnectReason::FrameTooLong,
+                        EchoServerError::FramingMismatch { .. } => DisconnectReason::FramingMismatch,
+                        EchoServerError::QueueOverflow { .. } => DisconnectReason::QueueOverflow,
+                        e => {
+                            context.emit(ServerEvent::WriteError { connection_id, error: e.to_string() });
+                            DisconnectReason::WriteFailed
+                        },
+                    }
+                },
+            };
+            context.emit(ServerEvent::ClientDisconnected { connection_id, peer_addr: client_addr, reason });
+            ConnectionTask::Served
+        }
+
+        /// Helper function to tell client over connection limit that server is busy
+        async fn reject_connection(
+            mut socket: Transport,
+            context: ConnectionContext,
+        ) -> ConnectionTask {
+            tracing::info!("Rejecting connection, server is busy");
+            context.metrics.connections_rejected.fetch_add(1, Ordering::Relaxed);
+            let result = match context.tls_acceptor {
+                Some(acceptor) => match accept_tls(&acceptor, socket, context.timeouts.handshake()).await {
+                    Ok(mut tls_stream) => send_notice(&mut tls_stream, context.framing, SERVER_BUSY_NOTICE).await,
+                    Err(e) => Err(e),
+                },
+                None => send_notice(&mut socket, context.framing, SERVER_BUSY_NOTICE).await,
+            };
+
+            if let Err(e) = result {
+                tracing::warn!(error = %e, "Couldnt notify client");
+            }
+            ConnectionTask::NotServed
+        }
+
+        let default_transform = self.transforms.get(&self.transform)
+            .ok_or_else(|| EchoServerError::UnknownTransform(self.transform.clone()))?;
+
+        let address = match self.listener.local_addr() {
+            Ok(address) => address.to_string(),
+            Err(_) => self.get_local_path().map(|path| path.display().to_string()).unwrap_or_default(),
+        };
+        tracing::info!(%address, "Started echo server");
+        let _ = self.events_tx.send(ServerEvent::Started { address });
+
+        let (shutdown_tx, mut shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+
+        let queue = MessageQueue::new(self.queue_capacity, self.queue_overflow_policy);
+        let msg_rx = queue.receiver();
+        let metrics = Arc::new(ServerMetrics::default());
+        // Holding msg_tx will prevent closing, dropping handler wont help
+
+        let shutdown_grace_period = self.shutdown_grace_period;
+        let connection_limit = self.connection_limit
+            .map(|(max_connections, policy)| (Arc::new(tokio::sync::Semaphore::new(max_connections)), policy));
+        let (stop_tx, stop_rx) = tokio::sync::watch::channel(false);
+        let connections_count = Arc::new(AtomicUsize::new(0));
+        let registry = ConnectionRegistry::default();
+        let framing = self.framing;
+        let events_tx = self.events_tx.clone();
+        let access_rules = Arc::new(RwLock::new(std::mem::take(&mut self.access_rules)));
+        let accept_access_rules = access_rules.clone();
+
+        let context = ConnectionContext {
+            queue,
+            metrics: metrics.clone(),
+            msg_handlers: Arc::new(self.msg_handlers),
+            tls_acceptor: self.tls_acceptor.clone(),
+            stop_rx,
+            connections_count: connections_count.clone(),
+            max_frame_length: self.max_frame_length,
+            frame_overflow_policy: self.frame_overflow_policy,
+            message_mode: self.message_mode,
+            framing: self.framing,
+            transforms: Arc::new(self.transforms),
+            default_transform,
+            delivery_mode: self.delivery_mode,
+            registry: registry.clone(),
+            events_tx: self.events_tx.clone(),
+            timeouts: self.timeouts,
+            connection_rate_limit: self.connection_rate_limit,
+            ip_rate_limiters: self.ip_rate_limit.map(|limit| Arc::new(IpRateLimiters::new(limit))),
+            rate_limit_policy: self.rate_limit_policy,
+        };
+
+        let metrics_task = self.metrics_listener.take()
+            .map(|metrics_listener| tokio::spawn(serve_metrics(metrics_listener, context.metrics.clone(), context.stop_rx.clone())));
+
+        // Spawn task to monitor incommingconenctions in background
+        let task_handler = tokio::spawn(async move {
+            let mut connections = tokio::task::JoinSet::new();
+            let mut next_connection_id = 1;
+
+            loop {
+                tokio::select! {
+                    _ = &mut shutdown_rx => {
+                        // This signal will be captured despite 
+                        // other branchin probress and cancel the other branch.
+                        tracing::debug!("Got shutdown signal");
+                        break;
+                    },
+                    Some(_) = connections.join_next(), if !connections.is_empty() => {
+                        // Reap finished connection tasks
+                    },
+                    incomming_connection = self.listener.accept() => {
+                        let (socket, address) = match incomming_connection {
+                            Ok(incomming_connection) => incomming_connection,
+                            Err(e) => {
+                                tracing::warn!(error = %e, "Incomming connection error");
+                                context.emit(ServerEvent::AcceptError { error: e.to_string() });
+                                continue;
+                            },
+                        };
+
+                        if let PeerAddr::Tcp(peer) = &address
+                            && !accept_access_rules.read().unwrap().is_allowed(&peer.ip())
+                        {
+                            tracing::info!(peer_addr = %address, "Connection denied by access rules");
+                            context.metrics.connections_rejected.fetch_add(1, Ordering::Relaxed);
+                            context.emit(ServerEvent::ConnectionRejected { peer_addr: address, reason: RejectReason::AccessDenied });
+                            continue;
+                        }
+
+                        let connection_id = next_connection_id;
+                        next_connection_id += 1;
+
+                        let context = context.clone();
+                        let span = tracing::info_span!("connection", connection_id, peer_addr = %address);
+                        let Some((limit, policy)) = connection_limit.as_ref() else {
+                            connections.spawn(serve_connection(socket, address, connection_id, None, context).instrument(span));
+                            continue;
+                        };
+
+                        match (limit.clone().try_acquire_owned(), policy) {
+                            (Ok(permit), _) => {
+                                connections.spawn(serve_connection(socket, address, connection_id, Some(permit), context).instrument(span));
+                            },
+                            (Err(_), AdmissionPolicy::Queue) => {
+                                let limit = limit.clone();
+                                let mut stop = context.stop_rx.clone();
+                                connections.spawn(async move {
+                                    tracing::info!("Queueing connection, server is busy");
+                                    // Slot freed by connection closed on shutdown must not admit client
+                                    tokio::select! {
+                                        biased;
+                                        _ = stop.changed() => ConnectionTask::NotServed,
+                                        Ok(permit) = limit.acquire_owned() => serve_connection(socket, address, connection_id, Some(permit), context).await,
+                                    }
+                                }.instrument(span));
+                            },
+                            (Err(_), AdmissionPolicy::Reject) => {
+                                context.emit(ServerEvent::ConnectionRejected { peer_addr: address, reason: RejectReason::ServerBusy });
+                                connections.spawn(reject_connection(socket, context).instrument(span));
+                            },
+                            (Err(_), AdmissionPolicy::Close) => {
+                                let _entered = span.enter();
+                                tracing::info!("Closing connection, server is busy");
+                                context.metrics.connections_rejected.fetch_add(1, Ordering::Relaxed);
+                                context.emit(ServerEvent::ConnectionRejected { peer_addr: address, reason: RejectReason::ServerBusy });
+                            },
+                        }
+
+                        // Here drop connection disconnetes client
+                    },
+                }
+            }
+
+            // Stop accepting and let open connections finish within grace period
+            drop(self.listener);
+            let _ = stop_tx.send(true);
+            if let Some(metrics_task) = metrics_task {
+                let _ = metrics_task.await;
+            }
+
+            let mut report = ShutdownReport::default();
+            let _ = tokio::time::timeout(shutdown_grace_period, async {
+                while let Some(result) = connections.join_next().await {
+                    match result {
+                        Ok(ConnectionTask::Served) => report.closed_cleanly += 1,
+                        Ok(ConnectionTask::NotServed) => {},
+                        Err(_) => report.aborted += 1,
+                    }
+                }
+            }).await;
+
+            // Tasks still rejecting clients are not counted, only connections being served
+            report.aborted += context.connections_count.load(Ordering::Relaxed);
+            connections.shutdown().await;
+            tracing::info!(closed_cleanly = report.closed_cleanly, aborted = report.aborted, "Server stopped");
+            context.emit(ServerEvent::ShutdownComplete(report));
+            report
+        });
+
+        Ok(EchoServerHandler {
+            shutdown_tx,
+            task_handler,
+            msg_rx,
+            metrics,
+            connections_count,
+            registry,
+            framing,
+            events_tx,
+            access_rules,
+        })
+    }
+}
+
+impl EchoServerHandler {
+    /// Stop accepting connections, notify connected clients and wait for them up to grace period
+    pub async fn shutdown(self) -> Result<ShutdownReport, EchoServerError> {
+        tracing::info!("Shutting down server");
+        self.shutdown_tx.send(()).map_err(|_| EchoServerError::KillFailed)?;
+
+        match self.task_handler.await {
+            Ok(report) => {
+                tracing::debug!("Server shutdown sucessfully");
+                Ok(report)
+            },
+            Err(_) => {
+                tracing::error!("Shutting down server failed");
+                Err(EchoServerError::KillFailed)
+            },
+        }
+    }
+
+    /// Number of clients being served right now
+    pub fn connections_count(&self) -> usize {
+        self.connections_count.load(Ordering::Relaxed)
+    }
+
+    /// Lifecycle events from now on, stream ends after shutdown completes
+    pub fn subscribe_events(&self) -> futures::stream::BoxStream<'static, ServerEvent> {
+        event_stream(self.events_tx.subscribe())
+    }
+
+    /// Active connections with their traffic statistics, ordered by id
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.registry.list()
+    }
+
+    /// Encode payload pushed by server, in line framing newline is added
+    fn push_frame(&self, payload: &[u8]) -> Bytes {
+        let mut message = [PUSH_PREFIX.as_bytes(), payload].concat();
+        if self.framing == Framing::Line && !message.ends_with(b"\n") {
+            message.push(b'\n');
+        }
+        Bytes::from(self.framing.encode(&message))
+    }
+
+    /// Send message to single client out of band, it is written between replies to client messages
+    pub fn send_to(&self, connection_id: u64, payload: &[u8]) -> Result<(), EchoServerError> {
+        match self.registry.send_to(connection_id, self.push_frame(payload)) {
+            Some(true) => Ok(()),
+            Some(false) => Err(EchoServerError::QueueOverflow { client: connection_id.to_string() }),
+            None => Err(EchoServerError::UnknownConnection(connection_id)),
+        }
+    }
+
+    /// Send message to every connected client out of band, returns number of clients it was queued for
+    pub fn broadcast(&self, payload: &[u8]) -> usize {
+        self.registry.broadcast(self.push_frame(payload), None)
+    }
+
+    /// Disconnect client, farewell line is sent to it first if given
+    pub fn kick(&self, connection_id: u64, farewell: Option<&str>) -> Result<(), EchoServerError> {
+        if self.registry.kick(connection_id, farewell.map(str::to_string)) {
+            Ok(())
+        } else {
+            Err(EchoServerError::UnknownConnection(connection_id))
+        }
+    }
+
+    /// Number of messages which did not fit into incoming messages queue
+    pub fn dropped_messages(&self) -> u64 {
+        self.metrics.queue_drops.load(Ordering::Relaxed)
+    }
+
+    /// Replace access rules, they are checked for connections accepted from now on
+    pub fn set_access_rules(&self, access_rules: AccessRules) {
+        *self.access_rules.write().unwrap() = access_rules;
+    }
+
+    pub fn access_rules(&self) -> AccessRules {
+        self.access_rules.read().unwrap().clone()
+    }
+
+    /// Current values of server counters and latency histogram
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    pub async fn await_incomming_msg(&mut self, duration: Option<Duration>) -> Result<Option<EchoMessage>, tokio::time::error::Elapsed> {
+        let receive = async { self.msg_rx.lock().await.recv().await };
+        if let Some(timeout_duration) = duration {
+            tokio::time::timeout(timeout_duration, receive).await
+        } else {
+            Ok(receive.await)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limit::RateLimitStatus;
+    use tokio::io::AsyncBufReadExt;
+    
+    #[tokio::test]
+    async fn test_shutdown() {
+        let echo_server = EchoServer::bind_any_local().await.unwrap();
+        let echo_serer_handler = echo_server.run().unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        echo_serer_handler.shutdown().await.unwrap();
+    }
+
+    const FRAMINGS: [Framing; 3] = [Framing::Line, Framing::LengthPrefixedU32, Framing::Varint];
+
+    async fn client_make_requests<A: tokio::net::ToSocketAddrs>(server_address: A, framing: Framing, requests: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut client_socket = tokio::net::TcpStream::connect(server_address).await?;
+        let (reader, mut writer) = client_socket.split();
+
+        let mut read_buffer = FrameReader::new(reader, framing, None, FrameOverflowPolicy::Disconnect);
+
+        for &request in requests {
+            writer.write_all(&framing.encode(request.as_bytes())).await?;
+            writer.flush().await?;
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+
+            let response = tokio::time::timeout(Duration::from_millis(500), read_buffer.read_frame()).await.unwrap().unwrap();
+
+            // println!("request={request}, response={response:?}");
+
+            assert_eq!(Some(Frame::Complete(request.as_bytes().to_vec())), response);
+        }
+
+        writer.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_echo_single_message() {
+        for framing in FRAMINGS {
+            let echo_server = EchoServer::bind_any_local().await.unwrap().with_framing(framing);
+            let server_address = echo_server.get_local_address().unwrap();
+            let echo_server_handle = echo_server.run().unwrap();
+
+            client_make_requests(server_address, framing, &["message\n"]).await.unwrap();
+            echo_server_handle.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_echo_multiple_messages() {
+        for framing in FRAMINGS {
+            let echo_server = EchoServer::bind_any_local().await.unwrap().with_framing(framing);
+            let server_address = echo_server.get_local_address().unwrap();
+            let echo_server_handle = echo_server.run().unwrap();
+
+            client_make_requests(server_address, framing, &["message\n", "aaa\n", "hello1234$%\n"]).await.unwrap();
+            echo_server_handle.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_echo_multiple_messages_with_queue() {
+        for framing in FRAMINGS {
+            let echo_server = EchoServer::bind_any_local().await.unwrap().with_framing(framing);
+            let server_address = echo_server.get_local_address().unwrap();
+            let mut echo_server_handle = echo_server.run().unwrap();
+
+            client_make_requests(server_address, framing, &["message\n", "1234\n"]).await.unwrap();
+
+            let msg1 = echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap();
+            assert_eq!(msg1.payload, "message\n");
+            assert_eq!(msg1.sequence, 0);
+
+            let msg2 = echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap();
+            assert_eq!(msg2.payload, "1234\n");
+            assert_eq!(msg2.sequence, 1);
+            assert_eq!(msg1.connection_id, msg2.connection_id);
+            assert_eq!(msg1.peer_addr, msg2.peer_addr);
+            assert!(msg1.received_at <= msg2.received_at);
+
+            assert!(echo_server_handle.await_incomming_msg(Some(Duration::from_millis(100))).await.is_err());
+            echo_server_handle.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_echo_multiple_clients_multiple_messages() {
+        for framing in FRAMINGS {
+            let clients_messages = [
+                vec!["Hello world!\n"],
+                vec!["Hello\n", "World\n"],
+                vec!["Foo\n", "Bar\n", "Buzz\n", "Donk\n", "Gotit\n"],
+                vec!["1234\n", "#$%^\n", "pddlaaass654sdjnt bksdf\n", "#34\n", "#7777777^\n", "asdASDasghj\n"],
+                vec!["Foo\n"; 103],
+            ];
+
+            let echo_server = EchoServer::bind_any_local().await.unwrap().with_framing(framing);
+            let server_address = echo_server.get_local_address().unwrap();
+            let mut echo_server_handle = echo_server.run().unwrap();
+
+            let messages_count = clients_messages.iter().map(Vec::len).sum::<usize>();
+            let task_handles = clients_messages
+                .iter()
+                .cloned()
+                .map(|msg| {
+                    tokio::spawn(async move {
+                        client_make_requests(server_address, framing, &msg).await.unwrap();
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            // Drain queue while clients are running, otherwise it would overflow
+            let mut received: std::collections::HashMap<u64, Vec<EchoMessage>> = std::collections::HashMap::new();
+            for _ in 0..messages_count {
+                let msg = echo_server_handle.await_incomming_msg(Some(Duration::from_millis(500))).await.unwrap().unwrap();
+                received.entry(msg.connection_id).or_default().push(msg);
+            }
+
+            for handle in task_handles {
+                handle.await.unwrap();
+            }
+
+            // Every client messages are queued in order they were sent
+            assert_eq!(received.len(), clients_messages.len());
+            for messages in received.values() {
+                assert!(messages.iter().enumerate().all(|(idx, msg)| msg.sequence == idx as u64));
+                let payloads = messages.iter().map(|msg| std::str::from_utf8(&msg.payload).unwrap()).collect::<Vec<_>>();
+                assert!(clients_messages.contains(&payloads));
+            }
+
+            // not reaching 
+            echo_server_handle.shutdown().await.unwrap();
+        }
+    }
+    
+
+    #[tokio::test]
+    async fn test_echo_multiple_messages_with_hook() {
+        for framing in FRAMINGS {
+            let messages_counter = Arc::new(std::sync::atomic::AtomicU32::new(0));
+            let messages_counter_copy = messages_counter.clone();
+
+            let echo_server = EchoServer::bind_any_local().await
+                .unwrap()
+                .with_framing(framing)
+                .with_listener(move |msg: &EchoMessage| {
+                    let value = messages_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    tracing::debug!(peer_addr = %msg.peer_addr, payload = %String::from_utf8_lossy(&msg.payload), count = value, "Hook got message");
+                });
+            let server_address = echo_server.get_local_address().unwrap();
+            let echo_server_handle = echo_server.run().unwrap();
+
+            client_make_requests(server_address, framing, &["message\n", "aaa\n", "hello1234$%\n"]).await.unwrap();
+            echo_server_handle.shutdown().await.unwrap();
+            assert_eq!(messages_counter_copy.load(std::sync::atomic::Ordering::Relaxed), 3);
+        }
+    }
+
+    /// Closes connection on "quit", does not reply to "secret"
+    struct Validator;
+
+    #[crate::hook::async_trait]
+    impl MessageHook for Validator {
+        async fn on_message(&self, message: &EchoMessage, reply: Bytes) -> HookAction {
+            match &message.payload[..] {
+                b"quit\n" => HookAction::Close,
+                b"secret\n" => HookAction::Suppress,
+                _ => HookAction::Reply(reply),
+            }
+        }
+    }
+
+    /// Prefixes reply with connection id
+    struct Enricher;
+
+    #[crate::hook::async_trait]
+    impl MessageHook for Enricher {
+        async fn on_message(&self, message: &EchoMessage, reply: Bytes) -> HookAction {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            HookAction::Reply(Bytes::from(format!("#{} {}", message.connection_id, String::from_utf8_lossy(&reply))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks_chain_transforms_replies() {
+        let messages_counter = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let messages_counter_copy = messages_counter.clone();
+
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_listener(move |_: &EchoMessage| {
+                messages_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            })
+            .with_listener(Validator)
+            .with_listener(Enricher);
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut echo_server_handle = echo_server.run().unwrap();
+
+        let client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let mut read_buffer = tokio::io::BufReader::new(client_socket);
+        read_buffer.write_all(b"hello\nsecret\nworld\nquit\n").await.unwrap();
+
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_millis(500), tokio::io::AsyncReadExt::read_to_string(&mut read_buffer, &mut response)).await.unwrap().unwrap();
+        assert_eq!(response, "#1 hello\n#1 world\n");
+
+        // Hooks do not affect queue
+        for expected in ["hello\n", "secret\n", "world\n", "quit\n"] {
+            assert_eq!(echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap().payload, expected);
+        }
+        echo_server_handle.shutdown().await.unwrap();
+        assert_eq!(messages_counter_copy.load(std::sync::atomic::Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn test_transform_selected_per_server_and_connection() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_transform("reverse");
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut echo_server_handle = echo_server.run().unwrap();
+
+        let client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let mut read_buffer = tokio::io::BufReader::new(client_socket);
+
+        let exchanges = [
+            ("Hello\n", "olleH\n"),
+            ("TRANSFORM uppercase\n", TRANSFORM_OK_NOTICE),
+            ("Hello\n", "HELLO\n"),
+            ("TRANSFORM rot13\n", TRANSFORM_OK_NOTICE),
+            ("Hello\n", "Uryyb\n"),
+            ("TRANSFORM missing\n", TRANSFORM_UNKNOWN_NOTICE),
+            ("TRANSFORM palindrome\n", TRANSFORM_OK_NOTICE),
+            ("kajak\n", "PALINDROME\n"),
+            ("Hello\n", "NOT_PALINDROME\n"),
+        ];
+        for (request, expected) in exchanges {
+            read_buffer.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            tokio::time::timeout(Duration::from_millis(500), read_buffer.read_line(&mut response)).await.unwrap().unwrap();
+            assert_eq!(response, expected);
+        }
+
+        // Commands are not queued, messages are queued untransformed
+        for expected in ["Hello\n", "Hello\n", "Hello\n", "kajak\n", "Hello\n"] {
+            assert_eq!(echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap().payload, expected);
+        }
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_server_transform() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_transforms(TransformRegistry::empty().with_transform("shout", |text| format!("{text}!")))
+            .with_transform(ECHO_TRANSFORM);
+        assert!(matches!(echo_server.run(), Err(EchoServerError::UnknownTransform(name)) if name == ECHO_TRANSFORM));
+    }
+
+    async fn read_line_within(client: &mut tokio::io::BufReader<tokio::net::TcpStream>, timeout: Duration) -> Result<String, tokio::time::error::Elapsed> {
+        let mut line = String::new();
+        tokio::time::timeout(timeout, client.read_line(&mut line)).await.map(|result| {
+            result.unwrap();
+            line
+        })
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_to_other_clients() {
+        for include_sender in [false, true] {
+            let echo_server = EchoServer::bind_any_local().await
+                .unwrap()
+                .with_delivery_mode(DeliveryMode::Broadcast { include_sender });
+            let server_address = echo_server.get_local_address().unwrap();
+            let echo_server_handle = echo_server.run().unwrap();
+            let timeout = Duration::from_millis(500);
+
+            let mut first = tokio::io::BufReader::new(tokio::net::TcpStream::connect(server_address).await.unwrap());
+            let mut second = tokio::io::BufReader::new(tokio::net::TcpStream::connect(server_address).await.unwrap());
+            assert!(read_line_within(&mut first, timeout).await.unwrap().starts_with("JOINED 2 "));
+
+            let mut third = tokio::io::BufReader::new(tokio::net::TcpStream::connect(server_address).await.unwrap());
+            for client in [&mut first, &mut second] {
+                assert!(read_line_within(client, timeout).await.unwrap().starts_with("JOINED 3 "));
+            }
+
+            first.write_all(b"hi\n").await.unwrap();
+            for client in [&mut second, &mut third] {
+                assert_eq!(read_line_within(client, timeout).await.unwrap(), "hi\n");
+            }
+            if include_sender {
+                assert_eq!(read_line_within(&mut first, timeout).await.unwrap(), "hi\n");
+            } else {
+                assert!(read_line_within(&mut first, Duration::from_millis(100)).await.is_err());
+            }
+
+            drop(third);
+            for client in [&mut first, &mut second] {
+                assert!(read_line_within(client, timeout).await.unwrap().starts_with("LEFT 3 "));
+            }
+
+            echo_server_handle.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_and_kick_connections() {
+        let echo_server = EchoServer::bind_any_local().await.unwrap();
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+        let timeout = Duration::from_millis(500);
+
+        let mut first = connect_served_client(server_address).await;
+        let second = connect_served_client(server_address).await;
+
+        first.write_all(b"hello\n").await.unwrap();
+        assert_eq!(read_line_within(&mut first, timeout).await.unwrap(), "hello\n");
+
+        let connections = echo_server_handle.connections();
+        assert_eq!(connections.iter().map(|info| info.connection_id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(connections[0].peer_addr, PeerAddr::Tcp(first.get_ref().local_addr().unwrap()));
+        assert_eq!(connections[1].peer_addr, PeerAddr::Tcp(second.get_ref().local_addr().unwrap()));
+        assert!(connections[0].connected_at <= connections[1].connected_at);
+        let first_stats = (connections[0].bytes_received, connections[0].bytes_sent, connections[0].messages_received, connections[0].messages_sent);
+        assert_eq!(first_stats, (13, 13, 2, 2));
+
+        echo_server_handle.kick(1, Some("bye")).unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(timeout, tokio::io::AsyncReadExt::read_to_string(&mut first, &mut response)).await.unwrap().unwrap();
+        assert_eq!(response, "bye\n");
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(echo_server_handle.connections().iter().map(|info| info.connection_id).collect::<Vec<_>>(), [2]);
+        assert!(matches!(echo_server_handle.kick(1, None), Err(EchoServerError::UnknownConnection(1))));
+
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    async fn next_event(events: &mut futures::stream::BoxStream<'static, ServerEvent>) -> Option<ServerEvent> {
+        use futures::StreamExt;
+        tokio::time::timeout(Duration::from_millis(500), events.next()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_events() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_queue_capacity(1, QueueOverflowPolicy::DropNewest);
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut events = echo_server.subscribe_events();
+        let echo_server_handle = echo_server.run().unwrap();
+        assert_eq!(next_event(&mut events).await, Some(ServerEvent::Started { address: server_address.to_string() }));
+
+        let mut first = connect_served_client(server_address).await;
+        let first_addr = PeerAddr::Tcp(first.get_ref().local_addr().unwrap());
+        assert_eq!(next_event(&mut events).await, Some(ServerEvent::ClientConnected { connection_id: 1, peer_addr: first_addr.clone() }));
+
+        first.write_all(b"dropped\n").await.unwrap();
+        assert_eq!(read_line_within(&mut first, Duration::from_millis(500)).await.unwrap(), "dropped\n");
+        let overflow = ServerEvent::QueueOverflow { connection_id: 1, policy: QueueOverflowPolicy::DropNewest };
+        assert_eq!(next_event(&mut events).await, Some(overflow));
+
+        drop(first);
+        let disconnected = ServerEvent::ClientDisconnected { connection_id: 1, peer_addr: first_addr, reason: DisconnectReason::ClientClosed };
+        assert_eq!(next_event(&mut events).await, Some(disconnected));
+
+        let second = connect_served_client(server_address).await;
+        let second_addr = PeerAddr::Tcp(second.get_ref().local_addr().unwrap());
+        assert_eq!(next_event(&mut events).await, Some(ServerEvent::ClientConnected { connection_id: 2, peer_addr: second_addr.clone() }));
+        // Queue is still full with first message of first client
+        let overflow = ServerEvent::QueueOverflow { connection_id: 2, policy: QueueOverflowPolicy::DropNewest };
+        assert_eq!(next_event(&mut events).await, Some(overflow));
+        echo_server_handle.kick(2, None).unwrap();
+        let disconnected = ServerEvent::ClientDisconnected { connection_id: 2, peer_addr: second_addr, reason: DisconnectReason::Kicked };
+        assert_eq!(next_event(&mut events).await, Some(disconnected));
+
+        let report = echo_server_handle.shutdown().await.unwrap();
+        assert_eq!(next_event(&mut events).await, Some(ServerEvent::ShutdownComplete(report)));
+        assert_eq!(next_event(&mut events).await, None);
+    }
+
+    async fn disconnect_reason(events: &mut futures::stream::BoxStream<'static, ServerEvent>) -> DisconnectReason {
+        loop {
+            if let Some(ServerEvent::ClientDisconnected { reason, .. }) = next_event(events).await {
+                return reason;
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout_disconnects() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_idle_timeout(Duration::from_secs(30));
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut events = echo_server.subscribe_events();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        let started = tokio::time::Instant::now();
+        let mut client = connect_served_client(server_address).await;
+
+        // Every message restarts idle timer
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        client.write_all(b"still here\n").await.unwrap();
+        assert_eq!(read_line_within(&mut client, Duration::from_millis(500)).await.unwrap(), "still here\n");
+
+        assert_eq!(read_line_within(&mut client, Duration::from_secs(60)).await.unwrap(), TIMEOUT_NOTICE);
+        assert!(started.elapsed() >= Duration::from_secs(50));
+        assert_eq!(disconnect_reason(&mut events).await, DisconnectReason::IdleTimeout);
+
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_timeout_disconnects() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_read_timeout(Duration::from_secs(5));
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut events = echo_server.subscribe_events();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        let mut client = connect_served_client(server_address).await;
+
+        // Waiting for next message is not limited by read timeout
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        client.write_all(b"hello\n").await.unwrap();
+        assert_eq!(read_line_within(&mut client, Duration::from_millis(500)).await.unwrap(), "hello\n");
+
+        let started = tokio::time::Instant::now();
+        client.write_all(b"never finished").await.unwrap();
+        // Paused clock jumps to the next timer, let server start its read timer before client timeout is the nearest one
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(read_line_within(&mut client, Duration::from_secs(60)).await.unwrap(), TIMEOUT_NOTICE);
+        assert!(started.elapsed() >= Duration::from_secs(5) && started.elapsed() < Duration::from_secs(6));
+        assert_eq!(disconnect_reason(&mut events).await, DisconnectReason::ReadTimeout);
+
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_lifetime_disconnects_active_client() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_max_lifetime(Duration::from_secs(20));
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut events = echo_server.subscribe_events();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        let started = tokio::time::Instant::now();
+        let mut client = connect_served_client(server_address).await;
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            client.write_all(b"hello\n").await.unwrap();
+            assert_eq!(read_line_within(&mut client, Duration::from_millis(500)).await.unwrap(), "hello\n");
+        }
+
+        assert_eq!(read_line_within(&mut client, Duration::from_secs(60)).await.unwrap(), TIMEOUT_NOTICE);
+        assert!(started.elapsed() >= Duration::from_secs(20) && started.elapsed() < Duration::from_secs(21));
+        assert_eq!(disconnect_reason(&mut events).await, DisconnectReason::LifetimeExceeded);
+
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_delays_messages() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_rate_limit(RateLimit::messages_per_second(2))
+            .unwrap();
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        let client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let mut client = tokio::io::BufReader::new(client_socket);
+        let started = tokio::time::Instant::now();
+        client.write_all(b"1\n2\n3\n4\n5\n6\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        // Burst of two messages goes through, the rest comes every half a second
+        for expected in ["1\n", "2\n", "3\n", "4\n", "5\n", "6\n"] {
+            assert_eq!(read_line_within(&mut client, Duration::from_secs(10)).await.unwrap(), expected);
+        }
+        assert!(started.elapsed() >= Duration::from_secs(2) && started.elapsed() < Duration::from_secs(3));
+
+        let info = &echo_server_handle.connections()[0];
+        assert_eq!(info.rate_limited_messages, 4);
+        assert_eq!(info.rate_limit, Some(RateLimitStatus { message_tokens: Some(0), byte_tokens: None }));
+        assert_eq!(info.ip_rate_limit, None);
+
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_rejects_messages() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_rate_limit(RateLimit::bytes_per_second(4))
+            .unwrap()
+            .with_rate_limit_policy(RateLimitPolicy::Reject);
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+        let timeout = Duration::from_millis(500);
+
+        let client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let mut client = tokio::io::BufReader::new(client_socket);
+        client.write_all(b"ab\ncd\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(read_line_within(&mut client, timeout).await.unwrap(), "ab\n");
+        assert_eq!(read_line_within(&mut client, timeout).await.unwrap(), RATE_LIMITED_NOTICE);
+
+        // Rejected message does not use up tokens
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        client.write_all(b"ef\n").await.unwrap();
+        assert_eq!(read_line_within(&mut client, timeout).await.unwrap(), "ef\n");
+        assert_eq!(echo_server_handle.connections()[0].rate_limited_messages, 1);
+
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_delay_interrupted_by_kick_and_shutdown() {
+        // Debt of first message delays second one for seconds
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_rate_limit(RateLimit::bytes_per_second(1))
+            .unwrap();
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+        let timeout = Duration::from_secs(2);
+
+        let mut kicked = tokio::io::BufReader::new(tokio::net::TcpStream::connect(server_address).await.unwrap());
+        kicked.write_all(b"hello\nhello\n").await.unwrap();
+        assert_eq!(read_line_within(&mut kicked, timeout).await.unwrap(), "hello\n");
+        let mut throttled = tokio::io::BufReader::new(tokio::net::TcpStream::connect(server_address).await.unwrap());
+        throttled.write_all(b"hello\nhello\n").await.unwrap();
+        assert_eq!(read_line_within(&mut throttled, timeout).await.unwrap(), "hello\n");
+
+        echo_server_handle.kick(1, Some("bye")).unwrap();
+        assert_eq!(read_line_within(&mut kicked, timeout).await.unwrap(), "bye\n");
+
+        let report = tokio::time::timeout(timeout, echo_server_handle.shutdown()).await.unwrap().unwrap();
+        assert_eq!(report.aborted, 0);
+        assert_eq!(read_line_within(&mut throttled, timeout).await.unwrap(), SHUTDOWN_NOTICE);
+    }
+
+    #[tokio::test]
+    async fn test_zero_rate_limit_rejected() {
+        let echo_server = EchoServer::bind_any_local().await.unwrap();
+        assert!(matches!(
+            echo_server.with_rate_limit(RateLimit::messages_per_second(0)),
+            Err(EchoServerError::InvalidRateLimit(_))
+        ));
+        let echo_server = EchoServer::bind_any_local().await.unwrap();
+        assert!(matches!(
+            echo_server.with_ip_rate_limit(RateLimit::messages_per_second(5).with_bytes_per_second(0)),
+            Err(EchoServerError::InvalidRateLimit(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ip_rate_limit_is_shared_by_connections() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_ip_rate_limit(RateLimit::messages_per_second(1))
+            .unwrap()
+            .with_rate_limit_policy(RateLimitPolicy::Reject);
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+        let timeout = Duration::from_millis(500);
+
+        let mut first = tokio::io::BufReader::new(tokio::net::TcpStream::connect(server_address).await.unwrap());
+        let mut second = tokio::io::BufReader::new(tokio::net::TcpStream::connect(server_address).await.unwrap());
+        first.write_all(b"hello\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(read_line_within(&mut first, timeout).await.unwrap(), "hello\n");
+        second.write_all(b"hello\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(read_line_within(&mut second, timeout).await.unwrap(), RATE_LIMITED_NOTICE);
+
+        let connections = echo_server_handle.connections();
+        assert_eq!(connections.iter().map(|info| info.rate_limited_messages).collect::<Vec<_>>(), [0, 1]);
+        assert!(connections.iter().all(|info| info.rate_limit.is_none()));
+        assert!(connections.iter().all(|info| info.ip_rate_limit == Some(RateLimitStatus { message_tokens: Some(0), byte_tokens: None })));
+
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_access_rules_replaced_at_runtime() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_access_rules(AccessRules::default().with_deny("127.0.0.0/8".parse().unwrap()));
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut events = echo_server.subscribe_events();
+        let echo_server_handle = echo_server.run().unwrap();
+        assert!(matches!(next_event(&mut events).await, Some(ServerEvent::Started { .. })));
+
+        let mut denied = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let denied_addr = PeerAddr::Tcp(denied.local_addr().unwrap());
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_millis(500), tokio::io::AsyncReadExt::read_to_end(&mut denied, &mut response)).await.unwrap().unwrap();
+        assert!(response.is_empty());
+        let rejected = ServerEvent::ConnectionRejected { peer_addr: denied_addr, reason: RejectReason::AccessDenied };
+        assert_eq!(next_event(&mut events).await, Some(rejected));
+        assert_eq!(echo_server_handle.metrics().connections_rejected, 1);
+
+        // Only IPv6 loopback is allowed now
+        let access_rules = AccessRules::default().with_allow("::1".parse().unwrap());
+        echo_server_handle.set_access_rules(access_rules.clone());
+        assert_eq!(echo_server_handle.access_rules(), access_rules);
+        let mut denied = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        tokio::time::timeout(Duration::from_millis(500), tokio::io::AsyncReadExt::read_to_end(&mut denied, &mut response)).await.unwrap().unwrap();
+        assert!(response.is_empty());
+
+        echo_server_handle.set_access_rules(AccessRules::default().with_allow("127.0.0.1".parse().unwrap()));
+        let _client = connect_served_client(server_address).await;
+        assert_eq!(echo_server_handle.connections().len(), 1);
+
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    async fn http_get(address: std::net::SocketAddr, path: &str) -> String {
+        let mut socket = tokio::net::TcpStream::connect(address).await.unwrap();
+        socket.write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes()).await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_millis(500), tokio::io::AsyncReadExt::read_to_string(&mut socket, &mut response)).await.unwrap().unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_metrics_snapshot_and_endpoint() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_queue_capacity(1, QueueOverflowPolicy::DropNewest)
+            .with_max_connections(1, AdmissionPolicy::Reject)
+            .with_metrics_endpoint("127.0.0.1:0").await
+            .unwrap();
+        let server_address = echo_server.get_local_address().unwrap();
+        let metrics_address = echo_server.get_metrics_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        let mut client = connect_served_client(server_address).await;
+        client.write_all(b"hello\n").await.unwrap();
+        assert_eq!(read_line_within(&mut client, Duration::from_millis(500)).await.unwrap(), "hello\n");
+
+        let mut rejected = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_millis(500), tokio::io::AsyncReadExt::read_to_string(&mut rejected, &mut response)).await.unwrap().unwrap();
+
+        let metrics = echo_server_handle.metrics();
+        assert_eq!((metrics.connections_accepted, metrics.connections_rejected), (1, 1));
+        assert_eq!((metrics.messages_echoed, metrics.queue_drops), (2, 1));
+        assert_eq!((metrics.bytes_read, metrics.bytes_written), (13, 13));
+        assert_eq!(metrics.handling_latency.count, 2);
+
+        // Scraper which never sends request does not hold up others
+        let _stalled_scraper = tokio::net::TcpStream::connect(metrics_address).await.unwrap();
+        let response = http_get(metrics_address, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP echo_connections_accepted_total"));
+        assert!(response.contains("echo_messages_echoed_total 2\n"));
+        assert!(response.contains("echo_message_handling_seconds_count 2\n"));
+        assert!(http_get(metrics_address, "/other").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        echo_server_handle.shutdown().await.unwrap();
+        assert!(tokio::net::TcpStream::connect(metrics_address).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_messages_without_reply() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_delivery_mode(DeliveryMode::Broadcast { include_sender: false })
+            .with_listener(Validator);
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        // Broadcast to nobody else and suppressed message are handled without reply, quit closes connection
+        let mut client = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        client.write_all(b"hello\nsecret\nquit\n").await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_millis(500), tokio::io::AsyncReadExt::read_to_end(&mut client, &mut response)).await.unwrap().unwrap();
+        assert!(response.is_empty());
+
+        let metrics = echo_server_handle.metrics();
+        assert_eq!(metrics.messages_echoed, 2);
+        assert_eq!(metrics.handling_latency.count, 2);
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_notifies_connected_client() {
+        let echo_server = EchoServer::bind_any_local().await.unwrap();
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        let client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let mut read_buffer = tokio::io::BufReader::new(client_socket);
+        read_buffer.write_all(b"message\n").await.unwrap();
+
+        let mut response_buffer = String::new();
+        read_buffer.read_line(&mut response_buffer).await.unwrap();
+        assert_eq!(response_buffer, "message\n");
+
+        let report = echo_server_handle.shutdown().await.unwrap();
+        assert_eq!(report, ShutdownReport { closed_cleanly: 1, aborted: 0 });
+
+        response_buffer.clear();
+        read_buffer.read_line(&mut response_buffer).await.unwrap();
+        assert_eq!(response_buffer, SHUTDOWN_NOTICE);
+
+        response_buffer.clear();
+        assert_eq!(read_buffer.read_line(&mut response_buffer).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_aborts_after_grace_period() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_shutdown_grace_period(Duration::from_millis(50))
+            .with_max_frame_length(64 * 1024 * 1024, FrameOverflowPolicy::Disconnect);
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        let mut idle_client = tokio::net::TcpStream::connect(server_address).await.unwrap();
+
+        // Client never reads its echo, so server gets stuck writing it back
+        let mut stuck_client = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let mut huge_message = vec![b'a'; 32 * 1024 * 1024];
+        huge_message.push(b'\n');
+        stuck_client.write_all(&huge_message).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let report = echo_server_handle.shutdown().await.unwrap();
+        assert_eq!(report, ShutdownReport { closed_cleanly: 1, aborted: 1 });
+
+        let mut response = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut idle_client, &mut response).await.unwrap();
+        assert_eq!(response, SHUTDOWN_NOTICE.as_bytes());
+    }
+
+    /// Connect and make sure connection is being served by getting one echo back
+    async fn connect_served_client(server_address: std::net::SocketAddr) -> tokio::io::BufReader<tokio::net::TcpStream> {
+        let client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let mut read_buffer = tokio::io::BufReader::new(client_socket);
+        read_buffer.write_all(b"served\n").await.unwrap();
+
+        let mut response_buffer = String::new();
+        tokio::time::timeout(Duration::from_millis(500), read_buffer.read_line(&mut response_buffer)).await.unwrap().unwrap();
+        assert_eq!(response_buffer, "served\n");
+        read_buffer
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_reject() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_max_connections(1, AdmissionPolicy::Reject);
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        let _served_client = connect_served_client(server_address).await;
+        assert_eq!(echo_server_handle.connections_count(), 1);
+
+        let mut rejected_client = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_millis(500), tokio::io::AsyncReadExt::read_to_end(&mut rejected_client, &mut response)).await.unwrap().unwrap();
+        assert_eq!(response, SERVER_BUSY_NOTICE.as_bytes());
+        assert_eq!(echo_server_handle.connections_count(), 1);
+
+        let report = echo_server_handle.shutdown().await.unwrap();
+        assert_eq!(report, ShutdownReport { closed_cleanly: 1, aborted: 0 });
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_close() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_max_connections(1, AdmissionPolicy::Close);
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        let _served_client = connect_served_client(server_address).await;
+
+        let mut closed_client = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_millis(500), tokio::io::AsyncReadExt::read_to_end(&mut closed_client, &mut response)).await.unwrap().unwrap();
+        assert!(response.is_empty());
+
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_queue() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_max_connections(1, AdmissionPolicy::Queue);
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        let mut served_client = connect_served_client(server_address).await;
+
+        let client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let mut queued_client = tokio::io::BufReader::new(client_socket);
+        queued_client.write_all(b"queued\n").await.unwrap();
+
+        let mut response_buffer = String::new();
+        assert!(tokio::time::timeout(Duration::from_millis(100), queued_client.read_line(&mut response_buffer)).await.is_err());
+        assert_eq!(echo_server_handle.connections_count(), 1);
+
+        // Releasing slot lets queued client in
+        served_client.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_millis(500), queued_client.read_line(&mut response_buffer)).await.unwrap().unwrap();
+        assert_eq!(response_buffer, "queued\n");
+        assert_eq!(echo_server_handle.connections_count(), 1);
+
+        queued_client.shutdown().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(echo_server_handle.connections_count(), 0);
+
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_report_skips_queued_connection() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_max_connections(1, AdmissionPolicy::Queue);
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        let _served_client = connect_served_client(server_address).await;
+        let mut queued_client = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Queued client was never served, it is neither closed cleanly nor aborted
+        let report = echo_server_handle.shutdown().await.unwrap();
+        assert_eq!(report, ShutdownReport { closed_cleanly: 1, aborted: 0 });
+        let mut response = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut queued_client, &mut response).await.unwrap();
+        assert!(response.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queue_overflow_drops_messages() {
+        for (policy, expected_queue) in [(QueueOverflowPolicy::DropNewest, ["1\n", "2\n"]), (QueueOverflowPolicy::DropOldest, ["3\n", "4\n"])] {
+            let echo_server = EchoServer::bind_any_local().await
+                .unwrap()
+                .with_queue_capacity(2, policy);
+            let server_address = echo_server.get_local_address().unwrap();
+            let mut echo_server_handle = echo_server.run().unwrap();
+
+            // Every message is echoed despite not fitting into queue
+            client_make_requests(server_address, Framing::Line, &["1\n", "2\n", "3\n", "4\n"]).await.unwrap();
+
+            for expected in expected_queue {
+                assert_eq!(echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap().payload, expected);
+            }
+            assert!(echo_server_handle.await_incomming_msg(Some(Duration::from_millis(100))).await.is_err());
+            assert_eq!(echo_server_handle.dropped_messages(), 2);
+            echo_server_handle.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_overflow_blocks_client() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_queue_capacity(1, QueueOverflowPolicy::Block);
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut echo_server_handle = echo_server.run().unwrap();
+
+        let mut client = connect_served_client(server_address).await;
+        client.write_all(b"blocked\n").await.unwrap();
+
+        let mut response = String::new();
+        assert!(tokio::time::timeout(Duration::from_millis(100), client.read_line(&mut response)).await.is_err());
+
+        // Making room in queue resumes client
+        assert_eq!(echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap().payload, "served\n");
+        tokio::time::timeout(Duration::from_millis(500), client.read_line(&mut response)).await.unwrap().unwrap();
+        assert_eq!(response, "blocked\n");
+        assert_eq!(echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap().payload, "blocked\n");
+
+        assert_eq!(echo_server_handle.dropped_messages(), 0);
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_queue_overflow_block_interrupted_by_kick_and_shutdown() {
+        // Nobody reads queue, so every connection blocks on its second message
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_queue_capacity(1, QueueOverflowPolicy::Block);
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+        let timeout = Duration::from_secs(2);
+
+        let mut kicked = connect_served_client(server_address).await;
+        kicked.write_all(b"blocked\n").await.unwrap();
+        let mut blocked = tokio::io::BufReader::new(tokio::net::TcpStream::connect(server_address).await.unwrap());
+        blocked.write_all(b"blocked\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        echo_server_handle.kick(1, Some("bye")).unwrap();
+        assert_eq!(read_line_within(&mut kicked, timeout).await.unwrap(), "bye\n");
+
+        let report = tokio::time::timeout(timeout, echo_server_handle.shutdown()).await.unwrap().unwrap();
+        assert_eq!(report.aborted, 0);
+        assert_eq!(read_line_within(&mut blocked, timeout).await.unwrap(), SHUTDOWN_NOTICE);
+    }
+
+    #[tokio::test]
+    async fn test_queue_overflow_disconnects_client() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_queue_capacity(1, QueueOverflowPolicy::Disconnect);
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut echo_server_handle = echo_server.run().unwrap();
+
+        let mut client = connect_served_client(server_address).await;
+        client.write_all(b"overflow\n").await.unwrap();
+
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_millis(500), tokio::io::AsyncReadExt::read_to_end(&mut client, &mut response)).await.unwrap().unwrap();
+        assert!(response.is_empty());
+
+        assert_eq!(echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap().payload, "served\n");
+        assert!(echo_server_handle.await_incomming_msg(Some(Duration::from_millis(100))).await.is_err());
+        assert_eq!(echo_server_handle.dropped_messages(), 1);
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_frame_too_long_disconnects() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_max_frame_length(8, FrameOverflowPolicy::Disconnect);
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut echo_server_handle = echo_server.run().unwrap();
+
+        let mut client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        client_socket.write_all(b"12345678\n123456789").await.unwrap();
+
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_millis(500), tokio::io::AsyncReadExt::read_to_end(&mut client_socket, &mut response)).await.unwrap().unwrap();
+        assert_eq!(response, format!("12345678\n{FRAME_TOO_LONG_NOTICE}").as_bytes());
+
+        assert_eq!(echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap().payload, "12345678\n");
+        assert!(echo_server_handle.await_incomming_msg(Some(Duration::from_millis(100))).await.is_err());
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_frame_too_long_truncates() {
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_max_frame_length(4, FrameOverflowPolicy::Truncate);
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut echo_server_handle = echo_server.run().unwrap();
+
+        client_make_requests(server_address, Framing::Line, &["abc\n", "abcd\n"]).await.unwrap();
+
+        let client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let mut read_buffer = tokio::io::BufReader::new(client_socket);
+        read_buffer.write_all(b"abcdefgh\nxy\n").await.unwrap();
+
+        let mut response_buffer = String::new();
+        read_buffer.read_line(&mut response_buffer).await.unwrap();
+        read_buffer.read_line(&mut response_buffer).await.unwrap();
+        assert_eq!(response_buffer, "abcd\nxy\n");
+
+        for expected in ["abc\n", "abcd\n", "abcd\n", "xy\n"] {
+            assert_eq!(echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap().payload, expected);
+        }
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_framing_mismatch_disconnects() {
+        // Line client talking to length-prefixed server and the other way around
+        let cases = [
+            (Framing::LengthPrefixedU32, b"message\n".to_vec()),
+            (Framing::Varint, b"hello\n".to_vec()),
+            (Framing::Line, Framing::LengthPrefixedU32.encode(b"message\n")),
+            (Framing::Line, Framing::Varint.encode(&[b'a'; 200])),
+        ];
+
+        for (framing, request) in cases {
+            let echo_server = EchoServer::bind_any_local().await.unwrap().with_framing(framing);
+            let server_address = echo_server.get_local_address().unwrap();
+            let mut echo_server_handle = echo_server.run().unwrap();
+
+            let mut client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+            client_socket.write_all(&request).await.unwrap();
+
+            let mut response = Vec::new();
+            tokio::time::timeout(Duration::from_millis(500), tokio::io::AsyncReadExt::read_to_end(&mut client_socket, &mut response)).await.unwrap().unwrap();
+            assert_eq!(response, framing.encode_line(FRAMING_MISMATCH_NOTICE), "framing={framing:?}");
+
+            assert!(echo_server_handle.await_incomming_msg(Some(Duration::from_millis(100))).await.is_err());
+            echo_server_handle.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_text_mode_drops_invalid_utf8() {
+        let echo_server = EchoServer::bind_any_local().await.unwrap();
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut echo_server_handle = echo_server.run().unwrap();
+
+        let mut client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        client_socket.write_all(b"abc\xff\xfe\x00\n").await.unwrap();
+
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_millis(500), tokio::io::AsyncReadExt::read_to_end(&mut client_socket, &mut response)).await.unwrap().unwrap();
+        assert!(response.is_empty());
+        assert!(echo_server_handle.await_incomming_msg(Some(Duration::from_millis(100))).await.is_err());
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_binary_mode_echoes_invalid_utf8() {
+        let messages_counter = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let messages_counter_copy = messages_counter.clone();
+
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_message_mode(MessageMode::Binary)
+            .with_listener(move |msg: &EchoMessage| {
+                assert_eq!(msg.payload, &b"\xff\xfe\x00\n"[..]);
+                messages_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            });
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut echo_server_handle = echo_server.run().unwrap();
+
+        let client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let mut read_buffer = tokio::io::BufReader::new(client_socket);
+        read_buffer.write_all(b"\xff\xfe\x00\n").await.unwrap();
+
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_millis(500), read_buffer.read_until(b'\n', &mut response)).await.unwrap().unwrap();
+        assert_eq!(response, b"\xff\xfe\x00\n");
+
+        let msg = echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap();
+        assert_eq!(msg.payload, &b"\xff\xfe\x00\n"[..]);
+        echo_server_handle.shutdown().await.unwrap();
+        assert_eq!(messages_counter_copy.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_replies_looking_like_server_messages_are_unchanged() {
+        for message_mode in [MessageMode::Text, MessageMode::Binary] {
+            let echo_server = EchoServer::bind_any_local().await
+                .unwrap()
+                .with_message_mode(message_mode);
+            let server_address = echo_server.get_local_address().unwrap();
+            let echo_server_handle = echo_server.run().unwrap();
+
+            let client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+            let mut read_buffer = tokio::io::BufReader::new(client_socket);
+            for message in [&b"\x10\x01\x02\n"[..], b"PUSH x\n", TIMEOUT_NOTICE.as_bytes(), SHUTDOWN_NOTICE.as_bytes()] {
+                read_buffer.write_all(message).await.unwrap();
+                let mut response = Vec::new();
+                tokio::time::timeout(Duration::from_millis(500), read_buffer.read_until(b'\n', &mut response)).await.unwrap().unwrap();
+                assert_eq!(response, message, "{message_mode:?}");
+            }
+            echo_server_handle.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls_server_ignores_plain_client() {
+        let pem = crate::tls::testing::SelfSignedPem::generate("tls_server_ignores_plain_client");
+
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_tls(&pem.cert_path, &pem.key_path)
+            .unwrap();
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut echo_server_handle = echo_server.run().unwrap();
+
+        // Plain text is not a valid TLS ClientHello, handshake fails and nothing is echoed
+        let mut client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        client_socket.write_all(b"message\n").await.unwrap();
+        let mut response = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_millis(500), tokio::io::AsyncReadExt::read_to_end(&mut client_socket, &mut response)).await.unwrap();
+        assert_ne!(response, b"message\n");
+        assert!(echo_server_handle.await_incomming_msg(Some(Duration::from_millis(100))).await.is_err());
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tls_handshake_timeout_disconnects() {
+        let pem = crate::tls::testing::SelfSignedPem::generate("tls_handshake_timeout_disconnects");
+
+        let echo_server = EchoServer::bind_any_local().await
+            .unwrap()
+            .with_tls(&pem.cert_path, &pem.key_path)
+            .unwrap()
+            .with_idle_timeout(Duration::from_secs(30))
+            .with_max_connections(1, AdmissionPolicy::Reject);
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut events = echo_server.subscribe_events();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        // Client which never starts handshake is disconnected and does not hold its slot
+        let started = tokio::time::Instant::now();
+        let mut client_socket = tokio::net::TcpStream::connect(server_address).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(60), tokio::io::AsyncReadExt::read_to_end(&mut client_socket, &mut response)).await.unwrap().unwrap();
+        assert!(response.is_empty());
+        assert!(started.elapsed() >= Duration::from_secs(30) && started.elapsed() < Duration::from_secs(31));
+
+        loop {
+            if let Some(ServerEvent::AcceptError { error }) = next_event(&mut events).await {
+                assert!(error.contains("timed out"), "{error}");
+                break;
+            }
+        }
+        assert_eq!(echo_server_handle.connections_count(), 0);
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_hook_gets_peer_credentials() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let socket_path = std::env::temp_dir().join(format!("echo_server_{}_unix_socket_hook.sock", std::process::id()));
+        let peers = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let peers_copy = peers.clone();
+
+        let echo_server = EchoServer::bind_unix_with_permissions(&socket_path, 0o600)
+            .unwrap()
+            .with_listener(move |msg: &EchoMessage| peers_copy.lock().unwrap().push(msg.peer_addr.to_string()));
+        assert!(echo_server.get_local_address().is_err());
+        assert_eq!(std::fs::metadata(&socket_path).unwrap().permissions().mode() & 0o777, 0o600);
+        let mut echo_server_handle = echo_server.run().unwrap();
+
+        let client_socket = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        let mut read_buffer = tokio::io::BufReader::new(client_socket);
+        read_buffer.write_all(b"message\n").await.unwrap();
+        let mut response = String::new();
+        read_buffer.read_line(&mut response).await.unwrap();
+        assert_eq!(response, "message\n");
+        assert_eq!(echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap().payload, "message\n");
+
+        let peers = peers.lock().unwrap().clone();
+        assert_eq!(peers.len(), 1);
+        assert!(peers[0].starts_with("uid="));
+        assert!(peers[0].ends_with(&format!(",pid={}", std::process::id())));
+
+        echo_server_handle.shutdown().await.unwrap();
+        assert!(!socket_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_with_tls_missing_files() {
+        let echo_server = EchoServer::bind_any_local().await.unwrap();
+        assert!(matches!(
+            echo_server.with_tls("missing_cert.pem", "missing_key.pem"),
+            Err(EchoServerError::TlsConfigError(_))
+        ));
+    }
+}
@@ -0,0 +1,89 @@
+use futures::StreamExt;
+
+use crate::{echo_server::{QueueOverflowPolicy, ShutdownReport}, transport::PeerAddr};
+
+/// Events buffered for slow subscribers, older ones are skipped when subscriber lags behind
+pub(crate) const EVENTS_CAPACITY: usize = 256;
+
+/// Why connection with client ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    ClientClosed,
+    ServerShutdown,
+    Kicked,
+    ClosedByHook,
+    FrameTooLong,
+    FramingMismatch,
+    QueueOverflow,
+    ReadFailed,
+    WriteFailed,
+    /// Client did not send any message within idle timeout
+    IdleTimeout,
+    /// Client did not finish started message within read timeout
+    ReadTimeout,
+    /// Connection was open longer than maximum lifetime
+    LifetimeExceeded,
+}
+
+/// Why connection was refused right after it was accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// Peer address is not allowed by access rules
+    AccessDenied,
+    /// Server is at connection limit
+    ServerBusy,
+}
+
+/// Lifecycle event of running server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerEvent {
+    Started {
+        address: String,
+    },
+    ClientConnected {
+        connection_id: u64,
+        peer_addr: PeerAddr,
+    },
+    ClientDisconnected {
+        connection_id: u64,
+        peer_addr: PeerAddr,
+        reason: DisconnectReason,
+    },
+    ConnectionRejected {
+        peer_addr: PeerAddr,
+        reason: RejectReason,
+    },
+    ReadError {
+        connection_id: u64,
+        error: String,
+    },
+    WriteError {
+        connection_id: u64,
+        error: String,
+    },
+    QueueOverflow {
+        connection_id: u64,
+        policy: QueueOverflowPolicy,
+    },
+    /// Accepting connection or TLS handshake failed
+    AcceptError {
+        error: String,
+    },
+    ShutdownComplete(ShutdownReport),
+}
+
+/// Stream of events, ends when server is shut down
+pub(crate) fn event_stream(events_rx: tokio::sync::broadcast::Receiver<ServerEvent>) -> futures::stream::BoxStream<'static, ServerEvent> {
+    futures::stream::unfold(events_rx, |mut events_rx| async move {
+        loop {
+            match events_rx.recv().await {
+                Ok(event) => return Some((event, events_rx)),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Event subscriber lagged");
+                },
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+    .boxed()
+}
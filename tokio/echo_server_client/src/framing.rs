@@ -0,0 +1,481 @@
+use tokio::io::{AsyncBufReadExt, AsyncRead};
+
+/// Default limit of single frame payload, protects from clients never sending newline
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 64 * 1024;
+
+/// Longest LEB128 encoding of u64
+const MAX_VARINT_LENGTH: usize = 10;
+
+/// How messages are delimited on the wire
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Every message ends with newline, payload cannot contain newline
+    #[default]
+    Line,
+    /// Payload preceded by its length as big-endian u32
+    LengthPrefixedU32,
+    /// Payload preceded by its length as unsigned LEB128 varint
+    Varint,
+}
+
+impl Framing {
+    /// Wrap payload into frame ready to be written.
+    /// In line mode payload is written as is, so it is expected to carry its newline.
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(payload.len() + MAX_VARINT_LENGTH);
+        match self {
+            Framing::Line => {},
+            Framing::LengthPrefixedU32 => {
+                let length = u32::try_from(payload.len()).expect("payload length exceeds u32");
+                frame.extend_from_slice(&length.to_be_bytes());
+            },
+            Framing::Varint => {
+                let mut length = payload.len() as u64;
+                while length >= 0x80 {
+                    frame.push((length as u8 & 0x7f) | 0x80);
+                    length >>= 7;
+                }
+                frame.push(length as u8);
+            },
+        }
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Encode text line, newline is kept only in line mode
+    pub(crate) fn encode_line(&self, line: &str) -> Vec<u8> {
+        match self {
+            Framing::Line => self.encode(line.as_bytes()),
+            _ => self.encode(line.trim_end_matches('\n').as_bytes()),
+        }
+    }
+}
+
+/// Best effort check whether newline framed text payload is actually length-prefixed frame.
+/// Only prefixes which cannot start text are recognized, u32 prefix of reasonably sized message starts with NUL
+/// and varint prefix of longer message is not valid UTF-8. Varint prefix of short message is valid text.
+fn looks_like_prefixed_frame(payload: &[u8]) -> bool {
+    match payload.first() {
+        None => false,
+        Some(0) => true,
+        Some(_) => matches!(
+            std::str::from_utf8(&payload[..payload.len().min(4)]),
+            Err(e) if e.valid_up_to() == 0 && e.error_len().is_some()
+        ),
+    }
+}
+
+/// Best effort check whether length prefix is actually beginning of text line
+fn looks_like_text(header: &[u8]) -> bool {
+    header.iter().all(|byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace())
+}
+
+/// Time to wait for rest of frame whose prefix looks like text and whose payload so far ends with newline.
+/// Line client waits for reply at this point, so frame not completed meanwhile is reported as mismatch.
+const TEXT_PREFIX_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// Wait for more data, without timeout when it is None
+async fn fill_buf_within<R: AsyncRead + Unpin>(
+    reader: &mut tokio::io::BufReader<R>,
+    timeout: Option<std::time::Duration>,
+) -> std::io::Result<&[u8]> {
+    let Some(timeout) = timeout else {
+        return reader.fill_buf().await;
+    };
+    tokio::time::timeout(timeout, reader.fill_buf()).await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "frame not completed within read timeout"))?
+}
+
+/// What to do with frame exceeding maximum frame length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameOverflowPolicy {
+    /// Report error to client and disconnect
+    Disconnect,
+    /// Keep first bytes up to the limit, rest of frame is discarded
+    Truncate,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Frame {
+    Complete(Vec<u8>),
+    Truncated(Vec<u8>),
+    TooLong,
+    /// Peer seems to use different framing
+    Mismatch,
+}
+
+/// Reads frames keeping at most max_frame_length bytes of payload in memory
+pub(crate) struct FrameReader<R> {
+    reader: tokio::io::BufReader<R>,
+    framing: Framing,
+    max_frame_length: Option<usize>,
+    overflow_policy: FrameOverflowPolicy,
+    buffer: Vec<u8>,
+    truncated: bool,
+    header: Vec<u8>,
+    payload_length: Option<usize>,
+    discard: u64,
+    expect_text: bool,
+    read_timeout: Option<std::time::Duration>,
+    /// Prefix of frame being read looks like beginning of text line
+    text_prefix: bool,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R, framing: Framing, max_frame_length: Option<usize>, overflow_policy: FrameOverflowPolicy) -> Self {
+        Self {
+            reader: tokio::io::BufReader::new(reader),
+            framing,
+            max_frame_length,
+            overflow_policy,
+            buffer: Vec::new(),
+            truncated: false,
+            header: Vec::new(),
+            payload_length: None,
+            discard: 0,
+            expect_text: false,
+            read_timeout: None,
+            text_prefix: false,
+        }
+    }
+
+    /// Line payloads are expected to be text, line starting like length prefix is reported as mismatch
+    pub fn expect_text(mut self) -> Self {
+        self.expect_text = true;
+        self
+    }
+
+    /// Limit time between reads once frame started arriving, exceeding it fails with TimedOut
+    pub fn with_read_timeout(mut self, read_timeout: Option<std::time::Duration>) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Change expected frame content between frames
+    pub fn set_expect_text(&mut self, expect_text: bool) {
+        self.expect_text = expect_text;
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.reader.get_ref()
+    }
+
+    /// Change framing, only safe before anything was read
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// Read next frame, in line mode payload keeps its newline. None means connection was closed.
+    /// Partially read frame is kept between calls, so it is safe to cancel.
+    pub async fn read_frame(&mut self) -> std::io::Result<Option<Frame>> {
+        match self.framing {
+            Framing::Line => self.read_line_frame().await,
+            Framing::LengthPrefixedU32 | Framing::Varint => self.read_prefixed_frame().await,
+        }
+    }
+
+    async fn read_line_frame(&mut self) -> std::io::Result<Option<Frame>> {
+        loop {
+            let read_timeout = self.read_timeout.filter(|_| self.in_frame());
+            let available = fill_buf_within(&mut self.reader, read_timeout).await?;
+            if available.is_empty() {
+                // Unterminated line is still handed over, same as read_line does
+                return Ok(self.take_frame());
+            }
+
+            let (chunk_length, complete) = match available.iter().position(|&byte| byte == b'\n') {
+                Some(position) => (position + 1, true),
+                None => (available.len(), false),
+            };
+
+            if !self.truncated {
+                self.buffer.extend_from_slice(&available[..chunk_length]);
+            }
+            self.reader.consume(chunk_length);
+
+            // Prefix is recognized from first bytes, without waiting for newline which may never come
+            if self.expect_text
+                && self.buffer.len() - chunk_length < 4
+                && looks_like_prefixed_frame(&self.buffer)
+            {
+                self.buffer.clear();
+                return Ok(Some(Frame::Mismatch));
+            }
+
+            if let Some(max_frame_length) = self.max_frame_length
+                && !self.truncated
+                && self.buffer.len() - usize::from(complete) > max_frame_length
+            {
+                match self.overflow_policy {
+                    FrameOverflowPolicy::Disconnect => {
+                        self.buffer.clear();
+                        return Ok(Some(Frame::TooLong));
+                    },
+                    FrameOverflowPolicy::Truncate => {
+                        self.buffer.truncate(max_frame_length);
+                        self.truncated = true;
+                    },
+                }
+            }
+
+            if complete {
+                if self.truncated {
+                    self.buffer.push(b'\n');
+                }
+                return Ok(self.take_frame());
+            }
+        }
+    }
+
+    async fn read_prefixed_frame(&mut self) -> std::io::Result<Option<Frame>> {
+        loop {
+            if let Some(payload_length) = self.payload_length
+                && self.buffer.len() == payload_length
+                && self.discard == 0
+            {
+                self.payload_length = None;
+                self.text_prefix = false;
+                let payload = std::mem::take(&mut self.buffer);
+                return Ok(Some(match std::mem::take(&mut self.truncated) {
+                    true => Frame::Truncated(payload),
+                    false => Frame::Complete(payload),
+                }));
+            }
+
+            let line_ended = self.text_prefix && self.buffer.last() == Some(&b'\n');
+            let read_timeout = match line_ended {
+                true => Some(self.read_timeout.map_or(TEXT_PREFIX_TIMEOUT, |read_timeout| read_timeout.min(TEXT_PREFIX_TIMEOUT))),
+                false => self.read_timeout.filter(|_| self.in_frame()),
+            };
+            let available = match fill_buf_within(&mut self.reader, read_timeout).await {
+                Err(e) if line_ended && e.kind() == std::io::ErrorKind::TimedOut => {
+                    self.payload_length = None;
+                    self.text_prefix = false;
+                    self.buffer.clear();
+                    return Ok(Some(Frame::Mismatch));
+                },
+                available => available?,
+            };
+            if available.is_empty() {
+                if self.header.is_empty() && self.payload_length.is_none() {
+                    return Ok(None);
+                }
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed in the middle of frame"));
+            }
+
+            let Some(payload_length) = self.payload_length else {
+                let consumed = match self.framing {
+                    Framing::Varint => match available.iter().position(|byte| byte & 0x80 == 0) {
+                        Some(position) => position + 1,
+                        None => available.len(),
+                    }.min(MAX_VARINT_LENGTH + 1 - self.header.len()),
+                    _ => (4 - self.header.len()).min(available.len()),
+                };
+                self.header.extend_from_slice(&available[..consumed]);
+                self.reader.consume(consumed);
+
+                let Some(length) = self.decode_header() else {
+                    if self.header.len() > MAX_VARINT_LENGTH {
+                        return Ok(Some(Frame::Mismatch));
+                    }
+                    continue;
+                };
+
+                let header = std::mem::take(&mut self.header);
+                self.text_prefix = looks_like_text(&header);
+                match self.max_frame_length {
+                    Some(max_frame_length) if length > max_frame_length as u64 => {
+                        if looks_like_text(&header) {
+                            return Ok(Some(Frame::Mismatch));
+                        }
+                        match self.overflow_policy {
+                            FrameOverflowPolicy::Disconnect => return Ok(Some(Frame::TooLong)),
+                            FrameOverflowPolicy::Truncate => {
+                                self.payload_length = Some(max_frame_length);
+                                self.discard = length - max_frame_length as u64;
+                                self.truncated = true;
+                            },
+                        }
+                    },
+                    _ => self.payload_length = Some(length as usize),
+                }
+                continue;
+            };
+
+            let consumed = if self.buffer.len() < payload_length {
+                let consumed = (payload_length - self.buffer.len()).min(available.len());
+                self.buffer.extend_from_slice(&available[..consumed]);
+                consumed
+            } else {
+                // Truncated frame, skip the rest of payload
+                let consumed = self.discard.min(available.len() as u64) as usize;
+                self.discard -= consumed as u64;
+                consumed
+            };
+            self.reader.consume(consumed);
+        }
+    }
+
+    /// Length from complete header, None when more header bytes are needed
+    fn decode_header(&self) -> Option<u64> {
+        match self.framing {
+            Framing::LengthPrefixedU32 if self.header.len() == 4 => {
+                let header: [u8; 4] = self.header[..].try_into().expect("header has 4 bytes");
+                Some(u32::from_be_bytes(header) as u64)
+            },
+            Framing::Varint if self.header.len() <= MAX_VARINT_LENGTH && self.header.last().is_some_and(|byte| byte & 0x80 == 0) => {
+                Some(self.header.iter()
+                    .enumerate()
+                    .fold(0u64, |length, (index, byte)| length | ((byte & 0x7f) as u64).wrapping_shl(7 * index as u32)))
+            },
+            _ => None,
+        }
+    }
+
+    /// Part of frame was already read
+    fn in_frame(&self) -> bool {
+        !self.buffer.is_empty() || self.truncated || !self.header.is_empty() || self.payload_length.is_some()
+    }
+
+    fn take_frame(&mut self) -> Option<Frame> {
+        if self.buffer.is_empty() && !self.truncated {
+            return None;
+        }
+
+        let payload = std::mem::take(&mut self.buffer);
+        if std::mem::take(&mut self.truncated) {
+            Some(Frame::Truncated(payload))
+        } else {
+            Some(Frame::Complete(payload))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_all_frames(input: &[u8], framing: Framing, max_frame_length: Option<usize>, policy: FrameOverflowPolicy) -> Vec<Frame> {
+        let mut reader = FrameReader::new(input, framing, max_frame_length, policy);
+        let mut frames = vec![];
+        while let Some(frame) = reader.read_frame().await.unwrap() {
+            let last = matches!(frame, Frame::TooLong | Frame::Mismatch);
+            frames.push(frame);
+            if last {
+                break;
+            }
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn test_unbounded_lines() {
+        let frames = read_all_frames(b"abc\nde\nf", Framing::Line, None, FrameOverflowPolicy::Disconnect).await;
+        assert_eq!(frames, vec![
+            Frame::Complete(b"abc\n".to_vec()),
+            Frame::Complete(b"de\n".to_vec()),
+            Frame::Complete(b"f".to_vec()),
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_timeout_applies_only_within_frame() {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut reader = FrameReader::new(server, Framing::Line, None, FrameOverflowPolicy::Disconnect)
+            .with_read_timeout(Some(std::time::Duration::from_secs(1)));
+
+        // Waiting for frame to start is not limited
+        assert!(tokio::time::timeout(std::time::Duration::from_secs(10), reader.read_frame()).await.is_err());
+
+        client.write_all(b"abc").await.unwrap();
+        assert_eq!(reader.read_frame().await.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_line_at_limit_is_complete() {
+        let frames = read_all_frames(b"abcd\n", Framing::Line, Some(4), FrameOverflowPolicy::Disconnect).await;
+        assert_eq!(frames, vec![Frame::Complete(b"abcd\n".to_vec())]);
+    }
+
+    #[tokio::test]
+    async fn test_line_over_limit_disconnect() {
+        let frames = read_all_frames(b"ab\nabcdef\nab\n", Framing::Line, Some(4), FrameOverflowPolicy::Disconnect).await;
+        assert_eq!(frames, vec![Frame::Complete(b"ab\n".to_vec()), Frame::TooLong]);
+    }
+
+    #[tokio::test]
+    async fn test_line_over_limit_truncate() {
+        let frames = read_all_frames(b"abcdef\nab\nabcdefgh", Framing::Line, Some(4), FrameOverflowPolicy::Truncate).await;
+        assert_eq!(frames, vec![
+            Frame::Truncated(b"abcd\n".to_vec()),
+            Frame::Complete(b"ab\n".to_vec()),
+            Frame::Truncated(b"abcd".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(Framing::Line.encode(b"ab\n"), b"ab\n");
+        assert_eq!(Framing::LengthPrefixedU32.encode(b"ab\n"), b"\x00\x00\x00\x03ab\n");
+        assert_eq!(Framing::Varint.encode(b"ab"), b"\x02ab");
+        assert_eq!(Framing::Varint.encode(&[0; 300])[..2], [0xac, 0x02]);
+        assert_eq!(Framing::LengthPrefixedU32.encode_line("ab\n"), b"\x00\x00\x00\x02ab");
+    }
+
+    #[tokio::test]
+    async fn test_prefixed_round_trip() {
+        let payloads: [&[u8]; 4] = [b"", b"a\nb\n", &[0xff; 200], &[7; 70000]];
+        for framing in [Framing::LengthPrefixedU32, Framing::Varint] {
+            let input = payloads.iter().flat_map(|payload| framing.encode(payload)).collect::<Vec<_>>();
+            let frames = read_all_frames(&input, framing, None, FrameOverflowPolicy::Disconnect).await;
+            let expected = payloads.iter().map(|payload| Frame::Complete(payload.to_vec())).collect::<Vec<_>>();
+            assert_eq!(frames, expected, "framing={framing:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prefixed_over_limit() {
+        for framing in [Framing::LengthPrefixedU32, Framing::Varint] {
+            let input = [framing.encode(b"abcdef"), framing.encode(b"ab")].concat();
+
+            let frames = read_all_frames(&input, framing, Some(4), FrameOverflowPolicy::Truncate).await;
+            assert_eq!(frames, vec![Frame::Truncated(b"abcd".to_vec()), Frame::Complete(b"ab".to_vec())], "framing={framing:?}");
+
+            let frames = read_all_frames(&input, framing, Some(4), FrameOverflowPolicy::Disconnect).await;
+            assert_eq!(frames, vec![Frame::TooLong], "framing={framing:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prefixed_unexpected_eof() {
+        let input = Framing::LengthPrefixedU32.encode(b"abcdef");
+        let mut reader = FrameReader::new(&input[..5], Framing::LengthPrefixedU32, None, FrameOverflowPolicy::Disconnect);
+        assert_eq!(reader.read_frame().await.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_mismatch_detection() {
+        let frames = read_all_frames(b"message\n", Framing::LengthPrefixedU32, Some(1024), FrameOverflowPolicy::Truncate).await;
+        assert_eq!(frames, vec![Frame::Mismatch]);
+
+        let frames = read_all_frames(&[0xff; 11], Framing::Varint, Some(1024), FrameOverflowPolicy::Disconnect).await;
+        assert_eq!(frames, vec![Frame::Mismatch]);
+
+        let input = Framing::LengthPrefixedU32.encode(b"no newline here");
+        let mut reader = FrameReader::new(&input[..], Framing::Line, None, FrameOverflowPolicy::Disconnect).expect_text();
+        assert_eq!(reader.read_frame().await.unwrap(), Some(Frame::Mismatch));
+        let mut reader = FrameReader::new(&input[..], Framing::Line, None, FrameOverflowPolicy::Disconnect);
+        assert_eq!(reader.read_frame().await.unwrap(), Some(Frame::Complete(input.clone())));
+
+        assert!(looks_like_prefixed_frame(&Framing::LengthPrefixedU32.encode(b"message\n")));
+        assert!(looks_like_prefixed_frame(&Framing::Varint.encode(&[b'a'; 200])));
+        assert!(!looks_like_prefixed_frame(b"message\n"));
+        assert!(!looks_like_prefixed_frame(b"\tindented\n"));
+        assert!(!looks_like_prefixed_frame(b"\x1b[1mbold\n"));
+        let mut reader = FrameReader::new(&b"\x1b[1mbold\n"[..], Framing::Line, None, FrameOverflowPolicy::Disconnect).expect_text();
+        assert_eq!(reader.read_frame().await.unwrap(), Some(Frame::Complete(b"\x1b[1mbold\n".to_vec())));
+        assert!(!looks_like_prefixed_frame("żółw\n".as_bytes()));
+    }
+}
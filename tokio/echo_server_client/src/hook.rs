@@ -0,0 +1,44 @@
+use bytes::Bytes;
+
+use crate::echo_server::EchoMessage;
+
+pub use async_trait::async_trait;
+
+/// Decision of message hook what happens with reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookAction {
+    /// Pass reply to next hook, reply returned by last hook is sent to client
+    Reply(Bytes),
+    /// Do not send anything back, remaining hooks are skipped
+    Suppress,
+    /// Close connection without reply, remaining hooks are skipped
+    Close,
+}
+
+/// Middleware called for every received message, hooks are chained in order they were added.
+/// Reply starts as the received payload after transform, in line framing it keeps its newline.
+#[async_trait]
+pub trait MessageHook: Send + Sync + 'static {
+    async fn on_message(&self, message: &EchoMessage, reply: Bytes) -> HookAction;
+}
+
+/// Plain closures only observe messages and pass reply unchanged
+#[async_trait]
+impl<F: Fn(&EchoMessage) + Send + Sync + 'static> MessageHook for F {
+    async fn on_message(&self, message: &EchoMessage, reply: Bytes) -> HookAction {
+        self(message);
+        HookAction::Reply(reply)
+    }
+}
+
+/// Run reply to message through hooks chain
+pub(crate) async fn run_hooks(hooks: &[Box<dyn MessageHook>], message: &EchoMessage, reply: Bytes) -> HookAction {
+    let mut action = HookAction::Reply(reply);
+    for hook in hooks {
+        let HookAction::Reply(reply) = action else {
+            break;
+        };
+        action = hook.on_message(message, reply).await;
+    }
+    action
+}
@@ -1,416 +1,645 @@
-use std::time::Duration;
+pub mod access;
+pub mod echo_server;
+pub mod echo_client;
+pub mod events;
+pub mod hook;
+pub mod metrics;
+pub mod registry;
+pub mod udp_echo_server;
+pub mod udp_echo_client;
+pub mod framing;
+pub mod pubsub;
+pub mod rate_limit;
+pub mod reconnect;
+pub mod tls;
+pub mod transform;
+pub mod transport;
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
 
-mod echo_server {
-    type EchoHook = dyn Fn(&str, &str) + 'static + Send + Sync;
+    use super::*;
 
-    use std::{sync::Arc, time::Duration};
-    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+    #[tokio::test]
+    async fn test_client_server_interaction() {
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
 
-    #[derive(Debug, thiserror::Error)]
-    pub enum EchoServerError {
-        #[error("IoError, reason='{0}'")]
-        IoError(#[from] tokio::io::Error),
-        
-        #[error("TaskJoinError, reason='{0}'")]
-        TaskJoinError(#[from] tokio::task::JoinError),
+        let mut client = echo_client::EchoClient::new(server_address).await.unwrap();
+        let message = "Hello world";
+        client.send_await(Some(Duration::from_millis(100)), message).await.unwrap();
 
-        #[error("KillFailed")]
-        KillFailed,
-    }
-    
-    pub struct EchoServer {
-        listener: tokio::net::TcpListener,
-        queue_capacity: usize,
-        msg_handler: Option<Arc::<EchoHook>>,
+        server_handler.shutdown().await.unwrap();
     }
 
-    pub struct EchoServerHandler {
-        shutdown_tx: tokio::sync::oneshot::Sender<()>,
-        task_handler: tokio::task::JoinHandle<()>,
-        msg_rx: tokio::sync::mpsc::Receiver<String>, // no longer shutdown at drop
-    }
-    
-    impl EchoServer {
-        /// Bind listener to address ready to be started
-        pub async fn bind<A: tokio::net::ToSocketAddrs>(addr: A) -> Result<Self, EchoServerError> {
-            Ok(Self {
-                listener: tokio::net::TcpListener::bind(addr).await?,
-                queue_capacity: 32,
-                msg_handler: None
-            })
+    #[tokio::test]
+    async fn test_client_server_interaction_multiple_concurrent_clients() {
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
+
+        let clients_count = 10;
+        let message = "Hello world";
+        for _ in 0..clients_count {
+            let _h = tokio::spawn(async move {
+                let mut client = echo_client::EchoClient::new(server_address).await.unwrap();
+                client.send_await(Some(Duration::from_millis(100)), message).await.unwrap();
+            });
         }
 
-        pub fn with_listener<F: Fn(&str, &str) + 'static + Send + Sync>(mut self, msg_handler: F) -> Self {
-            self.msg_handler = Some(Arc::new(msg_handler));
-            self
-        }
+        server_handler.shutdown().await.unwrap();
+    }
 
-        pub async fn bind_any_local() -> Result<Self, EchoServerError> {
-            Self::bind("127.0.0.1:0").await
-        }
+    #[tokio::test]
+    async fn test_client_server_interaction_multiple_parallel_clients() {
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
 
-        pub fn get_local_address(&self) -> std::io::Result<std::net::SocketAddr> {
-            self.listener.local_addr()
-        }
+        let clients_count = 20;
+        let message = "Hello world";
 
-        /// Run listening in background
-        #[must_use = "EchoServerHandler must be stored to keep the server alive"]
-        pub fn run(self) -> Result<EchoServerHandler, EchoServerError> {
-            /// Helper function to process messages in connections
-            async fn handle_connection(
-                mut stream: tokio::net::TcpStream, 
-                client_addr: std::net::SocketAddr, 
-                msg_tx: tokio::sync::mpsc::Sender<String>,
-                msg_handler: Option<Arc<EchoHook>>
-            ) {
-                println!("Incomming connection {client_addr:?}");
-                let (reader, mut writer) = stream.split();
-
-                let mut read_buffer = tokio::io::BufReader::new(reader);
-                let mut line_buf = String::new();
-
-                loop {
-                    match read_buffer.read_line(&mut line_buf).await {
-                        Ok(0) => {
-                            println!("Client {client_addr:?} closed connection");
-                            break;
-                        },
-                        Ok(_) => {
-                            if let Err(e) = msg_tx.try_send(line_buf.clone()) {
-                                println!("Couldnt queue messages from {client_addr:?} reason {e}");
-                            }
-
-                            if let Some(handler) = msg_handler.as_ref() {
-                                handler(&client_addr.to_string(), &line_buf);
-                            }
-
-                            if let Err(e) = writer.write_all(line_buf.as_bytes()).await {
-                                println!("Couldnt write back to client {client_addr:?} reason {e}");
-                            }
-                            writer.flush().await.unwrap();
-                            line_buf.clear();
-                        },
-                        Err(e) => {
-                            println!("Reading message from client {client_addr:?} failed, reason {e}");
-                            break;
-                        }
-                    }
-                }
-            }
+        let mut results = vec![];
 
-            let address = self.get_local_address()?;
-            println!("Started echo server at {address}");
-
-            let (shutdown_tx, mut shutdown_rx) = tokio::sync::oneshot::channel::<()>();
-
-            let (msg_tx, msg_rx) = tokio::sync::mpsc::channel(self.queue_capacity);
-            // Holding msg_tx will prevent closing, dropping handler wont help
-
-            let msg_handler = self.msg_handler.clone();
-
-            // Spawn task to monitor incommingconenctions in background
-            let task_handler = tokio::spawn(async move {
-                loop {
-                    tokio::select! {
-                        _ = &mut shutdown_rx => {
-                            // This signal will be captured despite 
-                            // other branchin probress and cancel the other branch.
-                            println!("Got shutdown signal");
-                            break;
-                        },
-                        incomming_connection = self.listener.accept() => {
-                            if let Ok((socket, address)) = incomming_connection {
-                                let tx = msg_tx.clone();
-                                let handler = msg_handler.clone();
-                                tokio::spawn(async move {
-                                    handle_connection(socket, address, tx, handler).await;
-                                });
-                            } else {
-                                println!("Incomming connection error");
-                            }
-
-                            // Here drop connection disconnetes client
-                        },
-                    }
-                }
+        for _ in 0..clients_count {
+            let h = tokio::task::spawn_blocking(move || {
+                let rt = tokio::runtime::Runtime::new().unwrap();
+                rt.block_on(async {
+                    let mut client = echo_client::EchoClient::new(server_address).await.unwrap();
+                    client.send_await(Some(Duration::from_millis(500)), message).await.unwrap();
+                })
             });
+            results.push(h);
+        }
 
-            Ok(EchoServerHandler {
-                shutdown_tx,
-                task_handler,
-                msg_rx
-            })
+        for thread_handler in results {
+            thread_handler.await.unwrap();
         }
+
+        server_handler.shutdown().await.unwrap();
+    }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_client_server_interaction_unix_socket() {
+        let socket_path = std::env::temp_dir().join(format!("echo_server_client_{}_interaction.sock", std::process::id()));
+        let server = echo_server::EchoServer::bind_unix(&socket_path)
+            .unwrap();
+        let server_handler = server.run().unwrap();
+
+        let mut client = echo_client::EchoClient::new_unix(&socket_path).await.unwrap();
+        let message = "Hello world";
+        client.send_await(Some(Duration::from_millis(100)), message).await.unwrap();
+
+        server_handler.shutdown().await.unwrap();
     }
 
-    impl EchoServerHandler {
-        pub async fn shutdown(self) -> Result<(), EchoServerError> {
-            println!("Shutting down server...");
-            self.shutdown_tx.send(()).map_err(|_| EchoServerError::KillFailed)?;
-
-            match self.task_handler.await {
-                Ok(_) => {
-                    println!("Server shutdown sucessfully!");
-                    Ok(())
-                },
-                Err(_) => {
-                    eprintln!("Shutting down server failed!");
-                    Err(EchoServerError::KillFailed)
-                },
-            }
-        }
+    #[tokio::test]
+    async fn test_client_server_interaction_tls() {
+        let pem = tls::testing::SelfSignedPem::generate("client_server_interaction_tls");
 
-        pub async fn await_incomming_msg(&mut self, duration: Option<Duration>) -> Result<Option<String>, tokio::time::error::Elapsed> {
-            if let Some(timeout_duration) = duration {
-                tokio::time::timeout(timeout_duration, self.msg_rx.recv()).await
-            } else {
-                Ok(self.msg_rx.recv().await)
-            }
-        }
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap()
+            .with_tls(&pem.cert_path, &pem.key_path)
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
+
+        let root_store = tls::load_root_store(&pem.cert_path).unwrap();
+        let mut client = echo_client::EchoClient::new_tls(server_address, "localhost", root_store).await.unwrap();
+        client.send_await(Some(Duration::from_millis(500)), "Hello world").await.unwrap();
+        client.send_await(Some(Duration::from_millis(500)), "Hello again").await.unwrap();
+
+        server_handler.shutdown().await.unwrap();
     }
 
-    #[cfg(test)]
-    mod tests {
-        use super::*;
-        
-        #[tokio::test]
-        async fn test_shutdown() {
-            let echo_server = EchoServer::bind_any_local().await.unwrap();
-            let echo_serer_handler = echo_server.run().unwrap();
-            tokio::time::sleep(Duration::from_secs(1)).await;
-            echo_serer_handler.shutdown().await.unwrap();
-        }
+    #[tokio::test]
+    async fn test_client_tls_untrusted_server() {
+        let server_pem = tls::testing::SelfSignedPem::generate("client_tls_untrusted_server");
+        let other_pem = tls::testing::SelfSignedPem::generate("client_tls_untrusted_other");
 
-        async fn client_make_requests<A: tokio::net::ToSocketAddrs>(server_address: A, requests: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
-            let mut client_socket = tokio::net::TcpStream::connect(server_address).await?;
-            let (reader, mut writer) = client_socket.split();
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap()
+            .with_tls(&server_pem.cert_path, &server_pem.key_path)
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
 
-            let mut read_buffer = tokio::io::BufReader::new(reader);
-            let mut response_buffer = String::new();
+        let root_store = tls::load_root_store(&other_pem.cert_path).unwrap();
+        let result = echo_client::EchoClient::new_tls(server_address, "localhost", root_store).await;
+        assert!(matches!(result, Err(echo_client::EchoClientError::IoError(_))));
 
-            for &request in requests {
-                writer.write_all(request.as_bytes()).await?;
-                writer.flush().await?;
+        server_handler.shutdown().await.unwrap();
+    }
 
-                tokio::time::sleep(Duration::from_millis(10)).await;
-    
-                tokio::time::timeout(Duration::from_millis(500), read_buffer.read_line(&mut response_buffer)).await.unwrap().unwrap();
+    #[tokio::test]
+    async fn test_client_server_interaction_binary() {
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap()
+            .with_message_mode(echo_server::MessageMode::Binary);
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
 
-                // println!("request={request}, response={response_buffer}");
-    
-                assert_eq!(request, response_buffer.as_str());
-                response_buffer.clear();
-            }
+        let mut client = echo_client::EchoClient::new(server_address).await.unwrap();
+        client.send_await_bytes(Some(Duration::from_millis(100)), &[0x00, 0xff, 0x80, 0x7f]).await.unwrap();
+        client.send_await_bytes(Some(Duration::from_millis(100)), "zażółć".as_bytes()).await.unwrap();
 
-            writer.shutdown().await?;
-            Ok(())
-        }
+        server_handler.shutdown().await.unwrap();
+    }
 
-        #[tokio::test]
-        async fn test_echo_single_message() {
-            let echo_server = EchoServer::bind_any_local().await.unwrap();
-            let server_address = echo_server.get_local_address().unwrap();
-            let echo_server_handle = echo_server.run().unwrap();
+    #[tokio::test]
+    async fn test_client_server_interaction_length_prefixed() {
+        for framing in [framing::Framing::LengthPrefixedU32, framing::Framing::Varint] {
+            let server = echo_server::EchoServer::bind_any_local().await
+                .unwrap()
+                .with_framing(framing)
+                .with_message_mode(echo_server::MessageMode::Binary);
+            let server_address = server.get_local_address().unwrap();
+            let server_handler = server.run().unwrap();
 
-            client_make_requests(server_address, &["message\n"]).await.unwrap();
-            echo_server_handle.shutdown().await.unwrap();
+            let mut client = echo_client::EchoClient::new(server_address).await.unwrap().with_framing(framing);
+            client.send_await(Some(Duration::from_millis(100)), "Hello world").await.unwrap();
+            client.send_await_bytes(Some(Duration::from_millis(100)), b"multi\nline\npayload").await.unwrap();
+            client.send_await_bytes(Some(Duration::from_millis(100)), &[]).await.unwrap();
+
+            server_handler.shutdown().await.unwrap();
         }
+    }
 
-        #[tokio::test]
-        async fn test_echo_multiple_messages() {
-            let echo_server = EchoServer::bind_any_local().await.unwrap();
-            let server_address = echo_server.get_local_address().unwrap();
-            let echo_server_handle = echo_server.run().unwrap();
+    #[tokio::test]
+    async fn test_client_server_framing_mismatch() {
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
 
-            client_make_requests(server_address, &["message\n", "aaa\n", "hello1234$%\n"]).await.unwrap();
-            echo_server_handle.shutdown().await.unwrap();
-        }
+        let mut client = echo_client::EchoClient::new(server_address).await
+            .unwrap()
+            .with_framing(framing::Framing::LengthPrefixedU32);
+        let result = client.send_await(Some(Duration::from_millis(100)), "Hello world").await;
+        assert!(matches!(result, Err(echo_client::EchoClientError::FramingMismatch(framing::Framing::LengthPrefixedU32))));
+
+        server_handler.shutdown().await.unwrap();
+    }
 
-        #[tokio::test]
-        async fn test_echo_multiple_messages_with_queue() {
-            let echo_server = EchoServer::bind_any_local().await.unwrap();
-            let server_address = echo_server.get_local_address().unwrap();
-            let mut echo_server_handle = echo_server.run().unwrap();
+    #[tokio::test]
+    async fn test_udp_client_server_interaction() {
+        let server = udp_echo_server::UdpEchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let mut server_handler = server.run().unwrap();
 
-            client_make_requests(server_address, &["message\n", "1234\n"]).await.unwrap();
+        let mut client = udp_echo_client::UdpEchoClient::new(server_address).await.unwrap();
+        client.send_await(Some(Duration::from_millis(100)), "Hello world").await.unwrap();
+        client.send_await(Some(Duration::from_millis(100)), "Hello again").await.unwrap();
 
-            let msg1 = echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap();
-            assert_eq!(msg1, "message\n");
+        assert_eq!(server_handler.await_incomming_msg(None).await.unwrap().unwrap().payload, "Hello world");
+        assert_eq!(server_handler.await_incomming_msg(None).await.unwrap().unwrap().payload, "Hello again");
+        server_handler.shutdown().await.unwrap();
+    }
 
-            let msg2 = echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap();
-            assert_eq!(msg2, "1234\n");
+    #[tokio::test]
+    async fn test_client_server_interaction_pubsub() {
+        use futures::StreamExt;
+
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap()
+            .with_delivery_mode(echo_server::DeliveryMode::PubSub);
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
+        let timeout = Some(Duration::from_millis(500));
+
+        let mut news_subscriber = echo_client::EchoClient::new(server_address).await.unwrap();
+        news_subscriber.subscribe(timeout, "news").await.unwrap();
+        let mut sport_subscriber = echo_client::EchoClient::new(server_address).await.unwrap();
+        sport_subscriber.subscribe(timeout, "sport").await.unwrap();
+
+        let mut publisher = echo_client::EchoClient::new(server_address).await.unwrap();
+        assert_eq!(publisher.publish(timeout, "news", "Hello world").await.unwrap(), 1);
+        assert_eq!(publisher.publish(timeout, "news", "Hello again").await.unwrap(), 1);
+        assert_eq!(publisher.publish(timeout, "weather", "Sunny").await.unwrap(), 0);
+
+        let publications = news_subscriber.publications().take(2).collect::<Vec<_>>().await;
+        let messages = publications.into_iter().map(|publication| publication.unwrap().message).collect::<Vec<_>>();
+        assert_eq!(messages, ["Hello world", "Hello again"]);
+        assert!(sport_subscriber.next_publication(Some(Duration::from_millis(100))).await.is_err());
+
+        // Publication arriving while waiting for command reply is kept for later
+        sport_subscriber.subscribe(timeout, "news").await.unwrap();
+        publisher.publish(timeout, "sport", "Goal").await.unwrap();
+        sport_subscriber.unsubscribe(timeout, "news").await.unwrap();
+        assert_eq!(publisher.publish(timeout, "news", "Nobody listens").await.unwrap(), 1);
+        let publication = sport_subscriber.next_publication(timeout).await.unwrap().unwrap();
+        assert_eq!((publication.topic.as_str(), publication.message.as_str()), ("sport", "Goal"));
+
+        server_handler.shutdown().await.unwrap();
+    }
 
-            assert!(echo_server_handle.await_incomming_msg(Some(Duration::from_millis(100))).await.is_err());
-            echo_server_handle.shutdown().await.unwrap();
-        }
+    #[tokio::test]
+    async fn test_client_server_interaction_pubsub_with_transform() {
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap()
+            .with_delivery_mode(echo_server::DeliveryMode::PubSub)
+            .with_transform("uppercase");
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
+        let timeout = Some(Duration::from_millis(500));
+
+        let mut subscriber = echo_client::EchoClient::new(server_address).await.unwrap();
+        subscriber.subscribe(timeout, "news").await.unwrap();
+
+        // Commands are parsed from received message, not from transformed reply
+        let mut publisher = echo_client::EchoClient::new(server_address).await.unwrap();
+        assert_eq!(publisher.publish(timeout, "NEWS", "Hello world").await.unwrap(), 0);
+        assert_eq!(publisher.publish(timeout, "news", "Hello world").await.unwrap(), 1);
+        let publication = subscriber.next_publication(timeout).await.unwrap().unwrap();
+        assert_eq!((publication.topic.as_str(), publication.message.as_str()), ("news", "Hello world"));
+
+        server_handler.shutdown().await.unwrap();
+    }
 
-        #[tokio::test]
-        async fn test_echo_multiple_clients_multiple_messages() {
-            let clients_messages = [
-                vec!["Hello world!\n"],
-                vec!["Hello\n", "World\n"],
-                vec!["Foo\n", "Bar\n", "Buzz\n", "Donk\n", "Gotit\n"],
-                vec!["1234\n", "#$%^\n", "pddlaaass654sdjnt bksdf\n", "#34\n", "#7777777^\n", "asdASDasghj\n"],
-                vec!["Foo\n"; 103],
-            ];
-
-            let echo_server = EchoServer::bind_any_local().await.unwrap();
-            let server_address = echo_server.get_local_address().unwrap();
-            let echo_server_handle = echo_server.run().unwrap();
-
-            let task_handles = clients_messages
-                .into_iter()
-                .map(|msg| {
-                    tokio::spawn(async move {
-                        client_make_requests(server_address, &msg).await.unwrap();
-                    })
-                })
-                .collect::<Vec<_>>();
+    #[tokio::test]
+    async fn test_client_server_interaction_server_push() {
+        use futures::StreamExt;
 
-            for handle in task_handles {
-                handle.await.unwrap();
-            }
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
+        let timeout = Some(Duration::from_millis(500));
 
-            // not reaching 
-            echo_server_handle.shutdown().await.unwrap();
-        }
-        
+        let mut first_client = echo_client::EchoClient::new(server_address).await.unwrap();
+        first_client.send_await(timeout, "Hello world").await.unwrap();
+        let mut second_client = echo_client::EchoClient::new(server_address).await.unwrap();
+        second_client.send_await(timeout, "Hello world").await.unwrap();
 
-        #[tokio::test]
-        async fn test_echo_multiple_messages_with_hook() {
-            let messages_counter = Arc::new(std::sync::atomic::AtomicU32::new(0));
-            let messages_counter_copy = messages_counter.clone();
+        server_handler.send_to(1, b"only for you").unwrap();
+        assert_eq!(server_handler.broadcast(b"for everyone"), 2);
+        assert!(matches!(server_handler.send_to(3, b"nobody"), Err(echo_server::EchoServerError::UnknownConnection(3))));
 
-            let echo_server = EchoServer::bind_any_local().await
-                .unwrap()
-                .with_listener(move |a, b| {
-                    let value = messages_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                    println!(">> {a}: {b} cnt={}", value);
-                });
-            let server_address = echo_server.get_local_address().unwrap();
-            let echo_server_handle = echo_server.run().unwrap();
-
-            client_make_requests(server_address, &["message\n", "aaa\n", "hello1234$%\n"]).await.unwrap();
-            echo_server_handle.shutdown().await.unwrap();
-            assert_eq!(messages_counter_copy.load(std::sync::atomic::Ordering::Relaxed), 3);
+        // Pushed messages do not disturb echo replies
+        first_client.send_await(timeout, "Hello again").await.unwrap();
+        assert_eq!(first_client.next_pushed(timeout).await.unwrap().unwrap(), "only for you");
+        let pushed = first_client.pushed_messages().next().await.unwrap().unwrap();
+        assert_eq!(pushed, "for everyone");
+
+        assert_eq!(second_client.next_pushed(timeout).await.unwrap().unwrap(), "for everyone");
+        assert!(second_client.next_pushed(Some(Duration::from_millis(100))).await.is_err());
+
+        server_handler.shutdown().await.unwrap();
+    }
+
+    struct CountingReconnectHandler {
+        reconnects: std::sync::Arc<std::sync::atomic::AtomicU32>,
+        resubscribe: Option<&'static str>,
+    }
+
+    #[hook::async_trait]
+    impl reconnect::ReconnectHandler for CountingReconnectHandler {
+        async fn on_reconnect(&mut self, client: &mut echo_client::EchoClient) -> Result<(), echo_client::EchoClientError> {
+            self.reconnects.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if let Some(topic) = self.resubscribe {
+                client.subscribe(Some(Duration::from_millis(500)), topic).await?;
+            }
+            Ok(())
         }
     }
-}
 
-mod echo_client {
-    use std::time::Duration;
+    #[tokio::test]
+    async fn test_client_reconnects_after_server_restart() {
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
+        let timeout = Some(Duration::from_millis(500));
 
-    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+        let reconnects = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let policy = reconnect::ReconnectPolicy::default().with_initial_delay(Duration::from_millis(20));
+        let mut client = echo_client::EchoClient::new(server_address).await.unwrap()
+            .with_reconnect(policy)
+            .with_reconnect_handler(CountingReconnectHandler { reconnects: reconnects.clone(), resubscribe: None });
+        client.send_await(timeout, "Hello world").await.unwrap();
 
-    #[derive(Debug, thiserror::Error)]
-    pub enum EchoClientError {
-        #[error("IoError, reason={0}")]
-        IoError(#[from] std::io::Error),
+        server_handler.shutdown().await.unwrap();
+        let server_handler = echo_server::EchoServer::bind(server_address).await.unwrap().run().unwrap();
 
-        #[error("IoError, reason={0}")]
-        TimeoutPassed(#[from] tokio::time::error::Elapsed),
+        client.send_await(timeout, "Hello again").await.unwrap();
+        assert_eq!(reconnects.load(std::sync::atomic::Ordering::SeqCst), 1);
 
-        #[error("BadResponse received='{0}'")]
-        BadResponse(String),
+        server_handler.shutdown().await.unwrap();
     }
-    pub struct EchoClient {
-        client_socket: tokio::net::TcpStream,
+
+    #[tokio::test]
+    async fn test_client_resubscribes_after_reconnect() {
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap()
+            .with_delivery_mode(echo_server::DeliveryMode::PubSub);
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
+        let timeout = Some(Duration::from_millis(500));
+
+        let reconnects = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let policy = reconnect::ReconnectPolicy::default().with_initial_delay(Duration::from_millis(20));
+        let mut subscriber = echo_client::EchoClient::new(server_address).await.unwrap()
+            .with_reconnect(policy)
+            .with_reconnect_handler(CountingReconnectHandler { reconnects: reconnects.clone(), resubscribe: Some("news") });
+        subscriber.subscribe(timeout, "news").await.unwrap();
+
+        server_handler.shutdown().await.unwrap();
+        let server_handler = echo_server::EchoServer::bind(server_address).await.unwrap()
+            .with_delivery_mode(echo_server::DeliveryMode::PubSub)
+            .run()
+            .unwrap();
+
+        // Failed command is retried after handler subscribed again
+        subscriber.subscribe(timeout, "sport").await.unwrap();
+        assert_eq!(reconnects.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let mut publisher = echo_client::EchoClient::new(server_address).await.unwrap();
+        assert_eq!(publisher.publish(timeout, "news", "Hello world").await.unwrap(), 1);
+        let publication = subscriber.next_publication(timeout).await.unwrap().unwrap();
+        assert_eq!((publication.topic.as_str(), publication.message.as_str()), ("news", "Hello world"));
+
+        server_handler.shutdown().await.unwrap();
     }
 
-    impl EchoClient {
-        pub async fn new<A: tokio::net::ToSocketAddrs>(addr: A) -> Result<Self, EchoClientError> {
-            Ok(Self {
-                client_socket: tokio::net::TcpStream::connect(addr).await?
-            })
-        }
+    #[tokio::test]
+    async fn test_client_reconnect_attempts_exhausted() {
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
+        let timeout = Some(Duration::from_millis(500));
+
+        let policy = reconnect::ReconnectPolicy::default()
+            .with_initial_delay(Duration::from_millis(10))
+            .with_max_attempts(3);
+        let mut client = echo_client::EchoClient::new(server_address).await.unwrap()
+            .with_reconnect(policy);
+        client.send_await(timeout, "Hello world").await.unwrap();
+
+        server_handler.shutdown().await.unwrap();
+        let result = client.send_await(timeout, "Hello again").await;
+        assert!(matches!(result, Err(echo_client::EchoClientError::ReconnectAttemptsExhausted { attempts: 3 })));
+    }
 
-        pub async fn send_await(
-            &mut self, 
-            timeout: Option<std::time::Duration>, 
-            msg: &str
-        ) -> Result<(), EchoClientError> {
-            let (read, mut write) = self.client_socket.split();
-            let mut buf_reader = tokio::io::BufReader::new(read);
+    #[tokio::test]
+    async fn test_client_pipelined_messages() {
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
+        let timeout = Some(Duration::from_millis(500));
 
-            write.write_all(msg.as_bytes()).await?;
-            write.write_all(b"\n").await?;
+        let mut client = echo_client::EchoClient::new(server_address).await.unwrap();
+        let messages = (0..100).map(|i| format!("Message {i}")).collect::<Vec<_>>();
+        let messages = messages.iter().map(String::as_str).collect::<Vec<_>>();
+        client.send_pipelined(timeout, &messages).await.unwrap();
+        client.send_await(timeout, "Hello world").await.unwrap();
 
-            let mut buf = String::new();
+        let result = client.send_pipelined(timeout, &[]).await;
+        assert!(result.is_ok());
 
-            if let Some(timeout_duration) = timeout {
-                tokio::time::timeout(timeout_duration, buf_reader.read_line(&mut buf)).await??;
-            } else {
-                buf_reader.read_line(&mut buf).await?;
-            }
+        server_handler.shutdown().await.unwrap();
+    }
 
-            if msg != buf.trim_end() {
-                Err(EchoClientError::BadResponse(buf))
-            } else {
-                Ok(())
-            }
-        }
+    #[tokio::test]
+    async fn test_client_cancelled_pipelined_send() {
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
+        let timeout = Some(Duration::from_millis(500));
+        let messages = (0..400).map(|i| format!("{i:0>60000}")).collect::<Vec<_>>();
+        let messages = messages.iter().map(String::as_str).collect::<Vec<_>>();
+
+        // Connection is left with unread replies, later requests fail instead of reading them
+        let mut client = echo_client::EchoClient::new(server_address).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(1), client.send_pipelined(None, &messages)).await.is_err());
+        let Err(echo_client::EchoClientError::IoError(e)) = client.send_await(timeout, "Hello world").await else {
+            panic!("request on interrupted connection succeeded");
+        };
+        assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe);
+
+        // With reconnect policy client connects again and request goes through
+        let policy = reconnect::ReconnectPolicy::default().with_initial_delay(Duration::from_millis(20));
+        let mut client = echo_client::EchoClient::new(server_address).await.unwrap()
+            .with_reconnect(policy);
+        assert!(tokio::time::timeout(Duration::from_millis(1), client.send_pipelined(None, &messages)).await.is_err());
+        client.send_await(timeout, "Hello world").await.unwrap();
+
+        server_handler.shutdown().await.unwrap();
     }
-}
 
-#[tokio::test]
-async fn test_client_server_interaction() {
-    let server = echo_server::EchoServer::bind_any_local().await
-        .unwrap();
-    let server_address = server.get_local_address().unwrap();
-    let server_handler = server.run().unwrap();
+    #[tokio::test]
+    async fn test_client_pipelined_large_batch() {
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
 
-    let mut client = echo_client::EchoClient::new(server_address).await.unwrap();
-    let message = "Hello world";
-    client.send_await(Some(Duration::from_millis(100)), message).await.unwrap();
+        // Batch much larger than socket buffers, replies have to be read while it is written
+        let mut client = echo_client::EchoClient::new(server_address).await.unwrap();
+        let messages = (0..400).map(|i| format!("{i:0>60000}")).collect::<Vec<_>>();
+        let messages = messages.iter().map(String::as_str).collect::<Vec<_>>();
+        client.send_pipelined(Some(Duration::from_secs(10)), &messages).await.unwrap();
 
-    server_handler.shutdown().await.unwrap();
-}
+        server_handler.shutdown().await.unwrap();
+    }
 
-#[tokio::test]
-async fn test_client_server_interaction_multiple_concurrent_clients() {
-    let server = echo_server::EchoServer::bind_any_local().await
-        .unwrap();
-    let server_address = server.get_local_address().unwrap();
-    let server_handler = server.run().unwrap();
-
-    let clients_count = 10;
-    let message = "Hello world";
-    for _ in 0..clients_count {
-        let _h = tokio::spawn(async move {
-            let mut client = echo_client::EchoClient::new(server_address).await.unwrap();
-            client.send_await(Some(Duration::from_millis(100)), message).await.unwrap();
+    #[tokio::test]
+    async fn test_client_keeps_lines_read_ahead() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Server answering first message with two lines at once
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 64];
+            let _ = socket.read(&mut buffer).await.unwrap();
+            socket.write_all(b"first\nsecond\n").await.unwrap();
+            let _ = socket.read(&mut buffer).await.unwrap();
         });
-    }
 
-    server_handler.shutdown().await.unwrap();
-}
+        let timeout = Some(Duration::from_millis(500));
+        let mut client = echo_client::EchoClient::new(server_address).await.unwrap();
+        client.send_await(timeout, "first").await.unwrap();
+        client.send_await(timeout, "second").await.unwrap();
 
-#[tokio::test]
-async fn test_client_server_interaction_multiple_parallel_clients() {
-    let server = echo_server::EchoServer::bind_any_local().await
-        .unwrap();
-    let server_address = server.get_local_address().unwrap();
-    let server_handler = server.run().unwrap();
+        drop(client);
+        server.await.unwrap();
+    }
 
-    let clients_count = 20;
-    let message = "Hello world";
+    #[tokio::test]
+    async fn test_client_split_into_sender_and_receiver() {
+        use futures::StreamExt;
 
-    let mut results = vec![];
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
+        let timeout = Some(Duration::from_millis(500));
 
-    for _ in 0..clients_count {
-        let h = tokio::task::spawn_blocking(move || {
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            rt.block_on(async {
-                let mut client = echo_client::EchoClient::new(server_address).await.unwrap();
-                client.send_await(Some(Duration::from_millis(500)), message).await.unwrap();
-            })
+        let client = echo_client::EchoClient::new(server_address).await.unwrap();
+        let (mut sender, receiver) = client.into_split();
+        let mut receiver = receiver.with_timeout(timeout);
+
+        let sending = tokio::spawn(async move {
+            for i in 0..10 {
+                sender.send(timeout, &format!("Message {i}")).await.unwrap();
+            }
+            sender
         });
-        results.push(h);
+        let replies = receiver.by_ref().take(10).map(Result::unwrap).collect::<Vec<_>>().await;
+        assert_eq!(replies, (0..10).map(|i| format!("Message {i}")).collect::<Vec<_>>());
+        let sender = sending.await.unwrap();
+
+        // Waiting for reply which does not come does not end stream
+        let mut receiver = receiver.with_timeout(Some(Duration::from_millis(50)));
+        assert!(matches!(receiver.next().await, Some(Err(echo_client::EchoClientError::TimeoutPassed(_)))));
+
+        let mut client = receiver.reunite(sender).unwrap();
+        client.send_await(timeout, "Hello again").await.unwrap();
+
+        server_handler.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_halves_of_different_clients_are_not_reunited() {
+        use futures::StreamExt;
+
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
+        let timeout = Some(Duration::from_millis(500));
+
+        let (mut first_sender, first_receiver) = echo_client::EchoClient::new(server_address).await.unwrap().into_split();
+        let (second_sender, second_receiver) = echo_client::EchoClient::new(server_address).await.unwrap().into_split();
+
+        let Err(echo_client::ReuniteError(second_sender, first_receiver)) = first_receiver.reunite(second_sender) else {
+            panic!("halves of different clients were reunited");
+        };
+        first_sender.send(timeout, "Hello world").await.unwrap();
+        let mut first_receiver = first_receiver.with_timeout(timeout);
+        assert_eq!(first_receiver.next().await.unwrap().unwrap(), "Hello world");
+
+        let mut first_client = first_receiver.reunite(first_sender).unwrap();
+        let mut second_client = second_receiver.reunite(second_sender).unwrap();
+        first_client.send_await(timeout, "Hello again").await.unwrap();
+        second_client.send_await(timeout, "Hello again").await.unwrap();
+
+        // Receiver reports when server goes away
+        let (_sender, mut receiver) = second_client.into_split();
+        server_handler.shutdown().await.unwrap();
+        let Some(Err(echo_client::EchoClientError::IoError(e))) = receiver.next().await else {
+            panic!("shutdown notice was yielded as reply");
+        };
+        assert_eq!(e.kind(), std::io::ErrorKind::ConnectionAborted);
+        assert!(receiver.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_echo_looking_like_push() {
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
+        let timeout = Some(Duration::from_millis(500));
+
+        let mut client = echo_client::EchoClient::new(server_address).await.unwrap();
+        client.send_await(timeout, "PUSH hello").await.unwrap();
+        client.send_await_bytes(timeout, b"PUSH \x10").await.unwrap();
+        assert!(client.next_pushed(Some(Duration::from_millis(100))).await.is_err());
+
+        // Push arriving while waiting for echo is still told apart from it
+        server_handler.send_to(1, b"only for you").unwrap();
+        client.send_await(timeout, "PUSH hello").await.unwrap();
+        assert_eq!(client.next_pushed(timeout).await.unwrap().unwrap(), "only for you");
+
+        server_handler.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_keeps_reply_arriving_while_waiting_for_push() {
+        use futures::StreamExt;
+
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let mut server_handler = server.run().unwrap();
+        let timeout = Some(Duration::from_millis(500));
+
+        let (mut sender, receiver) = echo_client::EchoClient::new(server_address).await.unwrap().into_split();
+        sender.send(timeout, "Hello world").await.unwrap();
+        let mut client = receiver.reunite(sender).unwrap();
+
+        // Reply is written before anything pushed after message was received
+        server_handler.await_incomming_msg(timeout).await.unwrap().unwrap();
+        server_handler.send_to(1, b"only for you").unwrap();
+        assert_eq!(client.next_pushed(timeout).await.unwrap().unwrap(), "only for you");
+
+        let (_sender, receiver) = client.into_split();
+        let mut receiver = receiver.with_timeout(timeout);
+        assert_eq!(receiver.next().await.unwrap().unwrap(), "Hello world");
+
+        server_handler.shutdown().await.unwrap();
     }
 
-    for thread_handler in results {
-        thread_handler.await.unwrap();
+    #[tokio::test]
+    async fn test_client_echo_looking_like_shutdown_notice() {
+        use futures::StreamExt;
+
+        let server = echo_server::EchoServer::bind_any_local().await
+            .unwrap();
+        let server_address = server.get_local_address().unwrap();
+        let server_handler = server.run().unwrap();
+        let timeout = Some(Duration::from_millis(500));
+
+        let policy = reconnect::ReconnectPolicy::default().with_initial_delay(Duration::from_millis(20));
+        let mut client = echo_client::EchoClient::new(server_address).await.unwrap()
+            .with_reconnect(policy);
+        client.send_await(timeout, echo_server::SHUTDOWN_NOTICE.trim_end()).await.unwrap();
+        client.send_await(timeout, "Hello world").await.unwrap();
+        assert_eq!(server_handler.metrics().connections_accepted, 1);
+
+        // Split receiver knows which echo it waits for too
+        let (mut sender, mut receiver) = client.into_split();
+        sender.send(timeout, echo_server::SHUTDOWN_NOTICE.trim_end()).await.unwrap();
+        assert_eq!(receiver.next().await.unwrap().unwrap(), echo_server::SHUTDOWN_NOTICE.trim_end());
+        server_handler.shutdown().await.unwrap();
+        let Some(Err(echo_client::EchoClientError::IoError(e))) = receiver.next().await else {
+            panic!("shutdown notice was yielded as reply");
+        };
+        assert_eq!(e.kind(), std::io::ErrorKind::ConnectionAborted);
     }
 
-    server_handler.shutdown().await.unwrap();
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn test_client_sender_interrupted_mid_frame_stays_broken() {
+        // Server never reading, so large message cannot be written within timeout
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            drop(socket);
+        });
+
+        let (mut sender, _receiver) = echo_client::EchoClient::new(server_address).await.unwrap().into_split();
+        let message = "a".repeat(16 * 1024 * 1024);
+        let result = sender.send(Some(Duration::from_millis(50)), &message).await;
+        assert!(matches!(result, Err(echo_client::EchoClientError::TimeoutPassed(_))));
+
+        let Err(echo_client::EchoClientError::IoError(e)) = sender.send(None, "Hello world").await else {
+            panic!("send after interrupted one succeeded");
+        };
+        assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe);
+
+        server.abort();
+    }
+}
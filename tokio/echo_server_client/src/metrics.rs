@@ -0,0 +1,229 @@
+use std::{
+    fmt::Write,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+    time::Duration,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::registry::ConnectionStats;
+
+/// Upper bounds in seconds of message handling latency buckets
+pub const LATENCY_BUCKETS: [f64; 12] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+/// Time given to scraper to send request headers
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_REQUEST_LENGTH: usize = 8192;
+
+/// Counts of observations, bucket is cumulative as in Prometheus
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HistogramSnapshot {
+    /// Pairs of upper bound in seconds and number of observations not greater than it
+    pub buckets: Vec<(f64, u64)>,
+    pub sum: Duration,
+    pub count: u64,
+}
+
+/// Point in time copy of server metrics
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub connections_accepted: u64,
+    pub connections_rejected: u64,
+    pub messages_echoed: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub queue_drops: u64,
+    pub handling_latency: HistogramSnapshot,
+}
+
+impl MetricsSnapshot {
+    /// Render in Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut output = String::new();
+        let counters = [
+            ("echo_connections_accepted_total", "Connections accepted and served", self.connections_accepted),
+            ("echo_connections_rejected_total", "Connections refused because of server limits", self.connections_rejected),
+            ("echo_messages_echoed_total", "Messages handled, including ones broadcast only to others or suppressed by hook", self.messages_echoed),
+            ("echo_bytes_read_total", "Bytes read from clients", self.bytes_read),
+            ("echo_bytes_written_total", "Bytes written to clients", self.bytes_written),
+            ("echo_queue_drops_total", "Messages dropped because incoming queue was full", self.queue_drops),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(output, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}");
+        }
+
+        let name = "echo_message_handling_seconds";
+        let _ = writeln!(output, "# HELP {name} Time from reading message until reply is written or message is otherwise handled\n# TYPE {name} histogram");
+        for (upper_bound, count) in &self.handling_latency.buckets {
+            let _ = writeln!(output, "{name}_bucket{{le=\"{upper_bound}\"}} {count}");
+        }
+        let latency = &self.handling_latency;
+        let _ = writeln!(output, "{name}_bucket{{le=\"+Inf\"}} {}", latency.count);
+        let _ = writeln!(output, "{name}_sum {}", latency.sum.as_secs_f64());
+        let _ = writeln!(output, "{name}_count {}", latency.count);
+        output
+    }
+}
+
+/// Histogram with fixed latency buckets
+#[derive(Debug, Default)]
+pub(crate) struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn observe(&self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|upper_bound| seconds <= *upper_bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_nanos.fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = LATENCY_BUCKETS.iter()
+            .zip(&self.buckets)
+            .map(|(upper_bound, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                (*upper_bound, cumulative)
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+            count: self.count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Server wide counters shared by all connections
+#[derive(Debug, Default)]
+pub(crate) struct ServerMetrics {
+    pub connections_accepted: AtomicU64,
+    pub connections_rejected: AtomicU64,
+    pub messages_echoed: AtomicU64,
+    pub queue_drops: AtomicU64,
+    /// Bytes of all connections, counted by wrapping each stream
+    pub traffic: Arc<ConnectionStats>,
+    pub handling_latency: LatencyHistogram,
+}
+
+impl ServerMetrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            connections_accepted: self.connections_accepted.load(Ordering::Relaxed),
+            connections_rejected: self.connections_rejected.load(Ordering::Relaxed),
+            messages_echoed: self.messages_echoed.load(Ordering::Relaxed),
+            bytes_read: self.traffic.bytes_received(),
+            bytes_written: self.traffic.bytes_sent(),
+            queue_drops: self.queue_drops.load(Ordering::Relaxed),
+            handling_latency: self.handling_latency.snapshot(),
+        }
+    }
+}
+
+/// Serve metrics over HTTP until server stops, only GET /metrics is supported
+pub(crate) async fn serve_metrics(
+    listener: tokio::net::TcpListener,
+    metrics: Arc<ServerMetrics>,
+    mut stop_rx: tokio::sync::watch::Receiver<bool>,
+) {
+    // Slow scraper must not hold up others, requests still running are aborted when server stops
+    let mut requests = tokio::task::JoinSet::new();
+    loop {
+        let socket = tokio::select! {
+            _ = stop_rx.changed() => break,
+            Some(_) = requests.join_next(), if !requests.is_empty() => continue,
+            incomming_connection = listener.accept() => match incomming_connection {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Incomming metrics connection error");
+                    continue;
+                },
+            },
+        };
+
+        requests.spawn(serve_request(socket, metrics.clone()));
+    }
+}
+
+async fn serve_request(mut socket: tokio::net::TcpStream, metrics: Arc<ServerMetrics>) {
+    let result = match tokio::time::timeout(REQUEST_TIMEOUT, read_request_line(&mut socket)).await {
+        Ok(Ok(request_line)) => respond(&mut socket, &request_line, &metrics).await,
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        tracing::debug!(error = %e, "Serving metrics failed");
+    }
+}
+
+/// Read request headers and return first line of request
+async fn read_request_line(socket: &mut tokio::net::TcpStream) -> std::io::Result<String> {
+    let mut request = Vec::new();
+    let mut chunk = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let length = socket.read(&mut chunk).await?;
+        if length == 0 || request.len() + length > MAX_REQUEST_LENGTH {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Incomplete HTTP request"));
+        }
+        request.extend_from_slice(&chunk[..length]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    Ok(request.lines().next().unwrap_or_default().to_string())
+}
+
+async fn respond(socket: &mut tokio::net::TcpStream, request_line: &str, metrics: &ServerMetrics) -> std::io::Result<()> {
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4", metrics.snapshot().to_prometheus()),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "Method Not Allowed\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let histogram = LatencyHistogram::default();
+        histogram.observe(Duration::from_micros(50));
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_secs(2));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 3);
+        assert_eq!(snapshot.sum, Duration::from_micros(2_003_050));
+        assert_eq!(snapshot.buckets[0], (0.0001, 1));
+        assert_eq!(snapshot.buckets[5], (0.005, 2));
+        assert_eq!(snapshot.buckets.last(), Some(&(1.0, 2)));
+    }
+
+    #[test]
+    fn test_prometheus_format() {
+        let metrics = ServerMetrics::default();
+        metrics.connections_accepted.fetch_add(2, Ordering::Relaxed);
+        metrics.handling_latency.observe(Duration::from_millis(1));
+
+        let output = metrics.snapshot().to_prometheus();
+        assert!(output.contains("# TYPE echo_connections_accepted_total counter\necho_connections_accepted_total 2\n"));
+        assert!(output.contains("echo_queue_drops_total 0\n"));
+        assert!(output.contains("# TYPE echo_message_handling_seconds histogram\n"));
+        assert!(output.contains("echo_message_handling_seconds_bucket{le=\"0.0005\"} 0\n"));
+        assert!(output.contains("echo_message_handling_seconds_bucket{le=\"0.001\"} 1\n"));
+        assert!(output.contains("echo_message_handling_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(output.contains("echo_message_handling_seconds_sum 0.001\n"));
+        assert!(output.contains("echo_message_handling_seconds_count 1\n"));
+    }
+}
@@ -0,0 +1,125 @@
+use bytes::Bytes;
+
+use crate::{framing::Framing, registry::ConnectionRegistry};
+
+/// `SUB topic` starts delivering publications from topic to connection
+pub const SUBSCRIBE_COMMAND: &str = "SUB";
+
+/// `UNSUB topic` stops delivering publications from topic to connection
+pub const UNSUBSCRIBE_COMMAND: &str = "UNSUB";
+
+/// `PUB topic message` sends message to every subscriber of topic
+pub const PUBLISH_COMMAND: &str = "PUB";
+
+/// Reply to SUB, followed by topic
+pub const SUBSCRIBED_NOTICE: &str = "SUBSCRIBED";
+
+/// Reply to UNSUB, followed by topic
+pub const UNSUBSCRIBED_NOTICE: &str = "UNSUBSCRIBED";
+
+/// Reply to PUB, followed by topic and number of subscribers message was delivered to
+pub const PUBLISHED_NOTICE: &str = "PUBLISHED";
+
+/// Prefix of line delivered to subscribers, followed by topic and message
+pub const PUBLICATION_PREFIX: &str = "MSG";
+
+/// Line sent to client which sent something else than pub/sub command
+pub const INVALID_COMMAND_NOTICE: &str = "INVALID_COMMAND\n";
+
+/// Message published to topic client is subscribed to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Publication {
+    pub topic: String,
+    pub message: String,
+}
+
+impl Publication {
+    /// Parse line delivered to subscriber, trailing newline is ignored
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        let (topic, message) = line.strip_prefix(PUBLICATION_PREFIX)?.strip_prefix(' ')?.split_once(' ')?;
+        Some(Self {
+            topic: topic.to_string(),
+            message: message.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command<'a> {
+    Subscribe(&'a str),
+    Unsubscribe(&'a str),
+    Publish {
+        topic: &'a str,
+        message: &'a str,
+    },
+}
+
+impl<'a> Command<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        let (command, arguments) = line.split_once(' ')?;
+        let command = match command {
+            SUBSCRIBE_COMMAND => Command::Subscribe(arguments),
+            UNSUBSCRIBE_COMMAND => Command::Unsubscribe(arguments),
+            PUBLISH_COMMAND => {
+                let (topic, message) = arguments.split_once(' ')?;
+                Command::Publish { topic, message }
+            },
+            _ => return None,
+        };
+
+        match command {
+            Command::Subscribe(topic) | Command::Unsubscribe(topic) | Command::Publish { topic, .. }
+                if topic.is_empty() || topic.contains(char::is_whitespace) => None,
+            command => Some(command),
+        }
+    }
+}
+
+/// Execute pub/sub command sent by connection, returns line to be sent back to it
+pub(crate) fn execute_command(registry: &ConnectionRegistry, connection_id: u64, framing: Framing, payload: &[u8]) -> String {
+    let Some(command) = std::str::from_utf8(payload).ok().and_then(Command::parse) else {
+        return INVALID_COMMAND_NOTICE.to_string();
+    };
+
+    match command {
+        Command::Subscribe(topic) => {
+            registry.subscribe(connection_id, topic);
+            format!("{SUBSCRIBED_NOTICE} {topic}\n")
+        },
+        Command::Unsubscribe(topic) => {
+            registry.unsubscribe(connection_id, topic);
+            format!("{UNSUBSCRIBED_NOTICE} {topic}\n")
+        },
+        Command::Publish { topic, message } => {
+            let publication = framing.encode_line(&format!("{PUBLICATION_PREFIX} {topic} {message}\n"));
+            let subscribers = registry.publish(topic, Bytes::from(publication));
+            format!("{PUBLISHED_NOTICE} {topic} {subscribers}\n")
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(Command::parse("SUB news\n"), Some(Command::Subscribe("news")));
+        assert_eq!(Command::parse("UNSUB news"), Some(Command::Unsubscribe("news")));
+        assert_eq!(Command::parse("PUB news hello world\n"), Some(Command::Publish { topic: "news", message: "hello world" }));
+        assert_eq!(Command::parse("PUB news\n"), None);
+        assert_eq!(Command::parse("SUB two topics\n"), None);
+        assert_eq!(Command::parse("SUB \n"), None);
+        assert_eq!(Command::parse("hello\n"), None);
+    }
+
+    #[test]
+    fn test_parse_publication() {
+        let publication = Publication::parse("MSG news hello world\n").unwrap();
+        assert_eq!(publication.topic, "news");
+        assert_eq!(publication.message, "hello world");
+        assert_eq!(Publication::parse("SUBSCRIBED news\n"), None);
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Allowed sustained rate, bursts up to one second worth of traffic are let through.
+/// Limit which is None is not checked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub messages_per_second: Option<u32>,
+    pub bytes_per_second: Option<u64>,
+}
+
+impl RateLimit {
+    pub fn messages_per_second(messages_per_second: u32) -> Self {
+        Self { messages_per_second: Some(messages_per_second), bytes_per_second: None }
+    }
+
+    pub fn bytes_per_second(bytes_per_second: u64) -> Self {
+        Self { messages_per_second: None, bytes_per_second: Some(bytes_per_second) }
+    }
+
+    /// Also limit bytes per second
+    pub fn with_bytes_per_second(mut self, bytes_per_second: u64) -> Self {
+        self.bytes_per_second = Some(bytes_per_second);
+        self
+    }
+
+    /// Rate of zero would never let any message through
+    pub fn is_valid(&self) -> bool {
+        self.messages_per_second != Some(0) && self.bytes_per_second != Some(0)
+    }
+}
+
+/// What to do with message exceeding rate limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RateLimitPolicy {
+    /// Stop reading from client until message fits into limit
+    #[default]
+    Delay,
+    /// Drop message and reply with RATE_LIMITED_NOTICE
+    Reject,
+}
+
+/// Tokens currently available in limiter buckets, None for limits which are not set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    pub message_tokens: Option<u64>,
+    pub byte_tokens: Option<u64>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Self { rate, tokens: rate, last_refill: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+
+    /// Time until amount can be taken, amount larger than bucket needs only full bucket
+    fn wait_time(&self, amount: f64) -> Duration {
+        let needed = amount.min(self.rate);
+        if self.tokens >= needed {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((needed - self.tokens) / self.rate)
+    }
+
+    /// Taking more than available leaves bucket in debt, paid off by later refills
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+
+    fn available(&self) -> u64 {
+        self.tokens.max(0.0) as u64
+    }
+}
+
+/// Message and byte buckets of single limit
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        let now = Instant::now();
+        Self {
+            messages: limit.messages_per_second.map(|rate| TokenBucket::new(rate as f64, now)),
+            bytes: limit.bytes_per_second.map(|rate| TokenBucket::new(rate as f64, now)),
+        }
+    }
+
+    fn buckets(&mut self, length: usize) -> impl Iterator<Item = (&mut TokenBucket, f64)> {
+        self.messages.iter_mut().map(|bucket| (bucket, 1.0))
+            .chain(self.bytes.iter_mut().map(move |bucket| (bucket, length as f64)))
+    }
+
+    fn wait_time(&mut self, length: usize, now: Instant) -> Duration {
+        self.buckets(length)
+            .map(|(bucket, amount)| {
+                bucket.refill(now);
+                bucket.wait_time(amount)
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn take(&mut self, length: usize) {
+        self.buckets(length).for_each(|(bucket, amount)| bucket.take(amount));
+    }
+
+    pub fn status(&self) -> RateLimitStatus {
+        RateLimitStatus {
+            message_tokens: self.messages.as_ref().map(TokenBucket::available),
+            byte_tokens: self.bytes.as_ref().map(TokenBucket::available),
+        }
+    }
+}
+
+/// Limiters message of single connection has to pass, own one and one shared with connections from the same IP
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ConnectionLimiters {
+    pub connection: Option<Arc<Mutex<RateLimiter>>>,
+    pub ip: Option<Arc<Mutex<RateLimiter>>>,
+}
+
+impl ConnectionLimiters {
+    /// Take tokens for message if all limiters allow it, otherwise return how long to wait before trying again
+    pub fn try_acquire(&self, length: usize) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut connection = self.connection.as_ref().map(|limiter| limiter.lock().unwrap());
+        let mut ip = self.ip.as_ref().map(|limiter| limiter.lock().unwrap());
+
+        let wait_time = connection.iter_mut().chain(ip.iter_mut())
+            .map(|limiter| limiter.wait_time(length, now))
+            .max()
+            .unwrap_or_default();
+        if !wait_time.is_zero() {
+            return Err(wait_time);
+        }
+
+        connection.iter_mut().chain(ip.iter_mut()).for_each(|limiter| limiter.take(length));
+        Ok(())
+    }
+}
+
+/// Limiters shared by connections from the same IP, limiter is forgotten when its last connection ends
+#[derive(Debug)]
+pub(crate) struct IpRateLimiters {
+    limit: RateLimit,
+    limiters: Mutex<HashMap<IpAddr, Weak<Mutex<RateLimiter>>>>,
+}
+
+impl IpRateLimiters {
+    pub fn new(limit: RateLimit) -> Self {
+        Self { limit, limiters: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn get(&self, ip: IpAddr) -> Arc<Mutex<RateLimiter>> {
+        let mut limiters = self.limiters.lock().unwrap();
+        limiters.retain(|_, limiter| limiter.strong_count() > 0);
+        if let Some(limiter) = limiters.get(&ip).and_then(Weak::upgrade) {
+            return limiter;
+        }
+
+        let limiter = Arc::new(Mutex::new(RateLimiter::new(self.limit)));
+        limiters.insert(ip, Arc::downgrade(&limiter));
+        limiter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiters(limit: RateLimit) -> ConnectionLimiters {
+        ConnectionLimiters {
+            connection: Some(Arc::new(Mutex::new(RateLimiter::new(limit)))),
+            ip: None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_burst_then_refill() {
+        let limiters = limiters(RateLimit::messages_per_second(2));
+        assert_eq!(limiters.try_acquire(10), Ok(()));
+        assert_eq!(limiters.try_acquire(10), Ok(()));
+        assert_eq!(limiters.try_acquire(10), Err(Duration::from_millis(500)));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(limiters.try_acquire(10), Ok(()));
+        assert_eq!(limiters.connection.as_ref().unwrap().lock().unwrap().status().message_tokens, Some(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bytes_limit_allows_large_message_with_full_bucket() {
+        let limiters = limiters(RateLimit::messages_per_second(100).with_bytes_per_second(10));
+        assert_eq!(limiters.try_acquire(25), Ok(()));
+        // Debt of large message has to be paid off first
+        assert_eq!(limiters.try_acquire(1), Err(Duration::from_millis(1600)));
+
+        tokio::time::advance(Duration::from_millis(1600)).await;
+        assert_eq!(limiters.try_acquire(1), Ok(()));
+        let status = limiters.connection.as_ref().unwrap().lock().unwrap().status();
+        assert_eq!(status, RateLimitStatus { message_tokens: Some(99), byte_tokens: Some(0) });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ip_limiter_is_shared_and_forgotten() {
+        let ip_limiters = IpRateLimiters::new(RateLimit::messages_per_second(1));
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let first = ConnectionLimiters { connection: None, ip: Some(ip_limiters.get(ip)) };
+        let second = ConnectionLimiters { connection: None, ip: Some(ip_limiters.get(ip)) };
+        let other = ConnectionLimiters { connection: None, ip: Some(ip_limiters.get(IpAddr::from([10, 0, 0, 1]))) };
+
+        assert_eq!(first.try_acquire(1), Ok(()));
+        assert!(second.try_acquire(1).is_err());
+        assert_eq!(other.try_acquire(1), Ok(()));
+
+        drop((first, second));
+        let third = ConnectionLimiters { connection: None, ip: Some(ip_limiters.get(ip)) };
+        assert_eq!(third.try_acquire(1), Ok(()));
+    }
+}
@@ -0,0 +1,108 @@
+use std::{hash::{BuildHasher, Hasher}, time::Duration};
+
+use crate::{echo_client::{EchoClient, EchoClientError}, hook::async_trait};
+
+/// How resilient client retries connecting after connection to server is lost.
+/// First attempt is made right away, delay before each next one grows exponentially up to max delay.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    multiplier: f64,
+    jitter: f64,
+    max_attempts: u32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: 5,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay before second attempt
+    pub fn with_initial_delay(mut self, initial_delay: Duration) -> Self {
+        self.initial_delay = initial_delay;
+        self
+    }
+
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Factor delay grows by after every failed attempt
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Fraction of delay, between 0 and 1, which is randomly cut off so clients do not reconnect all at once
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Delay before given attempt without jitter, attempts are counted from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        if attempt <= 1 {
+            return Duration::ZERO;
+        }
+        let delay = self.initial_delay.as_secs_f64() * self.multiplier.powi(attempt as i32 - 2);
+        Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+    }
+
+    /// Delay before given attempt with random part of jitter removed
+    pub(crate) fn jittered_delay(&self, attempt: u32) -> Duration {
+        // Every RandomState is seeded differently, good enough source of jitter
+        let random = std::collections::hash_map::RandomState::new().build_hasher().finish();
+        let cut = self.jitter * (random as f64 / u64::MAX as f64);
+        self.delay(attempt).mul_f64(1.0 - cut)
+    }
+}
+
+/// Called after client reconnected, before failed request is retried.
+/// Lets caller restore server side state, e.g. subscribe to topics again.
+#[async_trait]
+pub trait ReconnectHandler: Send + 'static {
+    async fn on_reconnect(&mut self, client: &mut EchoClient) -> Result<(), EchoClientError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_grows_up_to_max() {
+        let policy = ReconnectPolicy::default()
+            .with_initial_delay(Duration::from_millis(100))
+            .with_max_delay(Duration::from_millis(500));
+        let delays = (1..=6).map(|attempt| policy.delay(attempt).as_millis()).collect::<Vec<_>>();
+        assert_eq!(delays, [0, 100, 200, 400, 500, 500]);
+    }
+
+    #[test]
+    fn test_jitter_only_shortens_delay() {
+        let policy = ReconnectPolicy::default().with_jitter(0.5);
+        for _ in 0..100 {
+            let delay = policy.jittered_delay(3);
+            assert!(delay <= policy.delay(3) && delay >= policy.delay(3) / 2);
+        }
+        assert_eq!(policy.with_jitter(0.0).jittered_delay(3), policy.delay(3));
+    }
+}
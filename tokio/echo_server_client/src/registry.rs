@@ -0,0 +1,349 @@
+use std::{
+    collections::{HashMap, HashSet},
+    pin::Pin,
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex},
+    task::{Context, Poll},
+    time::SystemTime,
+};
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    rate_limit::{ConnectionLimiters, RateLimitStatus},
+    transport::PeerAddr,
+};
+
+/// Frames waiting to be written to single client, when full further frames for that client are dropped
+pub(crate) const OUTBOUND_QUEUE_CAPACITY: usize = 64;
+
+/// Snapshot of connection served by server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub connection_id: u64,
+    pub peer_addr: PeerAddr,
+    pub connected_at: SystemTime,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    /// Messages delayed or rejected because of rate limits
+    pub rate_limited_messages: u64,
+    /// State of connection own rate limiter, None when not limited
+    pub rate_limit: Option<RateLimitStatus>,
+    /// State of rate limiter shared with connections from the same IP, None when not limited
+    pub ip_rate_limit: Option<RateLimitStatus>,
+}
+
+/// Traffic counters updated by connection task
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionStats {
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    rate_limited_messages: AtomicU64,
+}
+
+impl ConnectionStats {
+    pub fn message_received(&self) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn message_sent(&self) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn message_rate_limited(&self) {
+        self.rate_limited_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+}
+
+/// Stream wrapper counting bytes going through it
+pub(crate) struct CountedStream<S> {
+    inner: S,
+    stats: Arc<ConnectionStats>,
+}
+
+impl<S> CountedStream<S> {
+    pub fn new(inner: S, stats: Arc<ConnectionStats>) -> Self {
+        Self { inner, stats }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountedStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.stats.bytes_received.fetch_add((buf.filled().len() - filled_before) as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountedStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            this.stats.bytes_sent.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+struct RegisteredConnection {
+    outbound_tx: tokio::sync::mpsc::Sender<Bytes>,
+    topics: HashSet<String>,
+    peer_addr: PeerAddr,
+    connected_at: SystemTime,
+    stats: Arc<ConnectionStats>,
+    limiters: ConnectionLimiters,
+    kick_tx: Option<tokio::sync::oneshot::Sender<Option<String>>>,
+}
+
+impl RegisteredConnection {
+    fn try_queue(&self, connection_id: u64, frame: Bytes) -> bool {
+        match self.outbound_tx.try_send(frame) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::debug!(connection_id, error = %e, "Couldnt queue outbound frame");
+                false
+            },
+        }
+    }
+}
+
+/// Connections currently served by server, used to deliver frames to other clients
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionRegistry {
+    connections: Arc<Mutex<HashMap<u64, RegisteredConnection>>>,
+}
+
+/// Removes connection from registry when connection task ends, also when it gets aborted
+pub(crate) struct Registration {
+    registry: ConnectionRegistry,
+    connection_id: u64,
+    leave_notice: Option<Bytes>,
+    /// Encoded frames to be written to client
+    pub outbound_rx: tokio::sync::mpsc::Receiver<Bytes>,
+    /// Request to disconnect client, optionally with farewell line
+    pub kick_rx: tokio::sync::oneshot::Receiver<Option<String>>,
+    pub stats: Arc<ConnectionStats>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.connections.lock().unwrap().remove(&self.connection_id);
+        if let Some(notice) = self.leave_notice.take() {
+            self.registry.broadcast(notice, None);
+        }
+    }
+}
+
+impl ConnectionRegistry {
+    /// Add connection, leave notice is broadcast to remaining clients when registration is dropped
+    pub fn register(&self, connection_id: u64, peer_addr: PeerAddr, leave_notice: Option<Bytes>) -> Registration {
+        let (outbound_tx, outbound_rx) = tokio::sync::mpsc::channel(OUTBOUND_QUEUE_CAPACITY);
+        let (kick_tx, kick_rx) = tokio::sync::oneshot::channel();
+        let stats = Arc::new(ConnectionStats::default());
+        let connection = RegisteredConnection {
+            outbound_tx,
+            topics: HashSet::new(),
+            peer_addr,
+            connected_at: SystemTime::now(),
+            stats: stats.clone(),
+            limiters: ConnectionLimiters::default(),
+            kick_tx: Some(kick_tx),
+        };
+        self.connections.lock().unwrap().insert(connection_id, connection);
+
+        Registration {
+            registry: self.clone(),
+            connection_id,
+            leave_notice,
+            outbound_rx,
+            kick_rx,
+            stats,
+        }
+    }
+
+    /// Snapshot of all registered connections ordered by id
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let connections = self.connections.lock().unwrap();
+        let mut list = connections.iter()
+            .map(|(connection_id, connection)| ConnectionInfo {
+                connection_id: *connection_id,
+                peer_addr: connection.peer_addr.clone(),
+                connected_at: connection.connected_at,
+                bytes_received: connection.stats.bytes_received.load(Ordering::Relaxed),
+                bytes_sent: connection.stats.bytes_sent.load(Ordering::Relaxed),
+                messages_received: connection.stats.messages_received.load(Ordering::Relaxed),
+                messages_sent: connection.stats.messages_sent.load(Ordering::Relaxed),
+                rate_limited_messages: connection.stats.rate_limited_messages.load(Ordering::Relaxed),
+                rate_limit: connection.limiters.connection.as_ref().map(|limiter| limiter.lock().unwrap().status()),
+                ip_rate_limit: connection.limiters.ip.as_ref().map(|limiter| limiter.lock().unwrap().status()),
+            })
+            .collect::<Vec<_>>();
+        list.sort_by_key(|info| info.connection_id);
+        list
+    }
+
+    /// Ask connection to disconnect, returns false if there is no such connection
+    pub fn kick(&self, connection_id: u64, farewell: Option<String>) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        let Some(kick_tx) = connections.get_mut(&connection_id).and_then(|connection| connection.kick_tx.take()) else {
+            return false;
+        };
+        // Connection which is already finishing does not need to be kicked
+        let _ = kick_tx.send(farewell);
+        true
+    }
+
+    /// Queue encoded frame to every connection except one, returns number of clients frame was queued for
+    pub fn broadcast(&self, frame: Bytes, except: Option<u64>) -> usize {
+        let connections = self.connections.lock().unwrap();
+        connections.iter()
+            .filter(|(connection_id, _)| Some(**connection_id) != except)
+            .filter(|(connection_id, connection)| connection.try_queue(**connection_id, frame.clone()))
+            .count()
+    }
+
+    /// Queue encoded frame to single connection, None if there is no such connection, false if its queue is full
+    pub fn send_to(&self, connection_id: u64, frame: Bytes) -> Option<bool> {
+        let connections = self.connections.lock().unwrap();
+        connections.get(&connection_id).map(|connection| connection.try_queue(connection_id, frame))
+    }
+
+    /// Expose rate limiters of connection in its info
+    pub fn set_limiters(&self, connection_id: u64, limiters: ConnectionLimiters) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&connection_id) {
+            connection.limiters = limiters;
+        }
+    }
+
+    pub fn subscribe(&self, connection_id: u64, topic: &str) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&connection_id) {
+            connection.topics.insert(topic.to_string());
+        }
+    }
+
+    pub fn unsubscribe(&self, connection_id: u64, topic: &str) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&connection_id) {
+            connection.topics.remove(topic);
+        }
+    }
+
+    /// Queue encoded frame to every connection subscribed to topic, returns number of subscribers frame was queued for
+    pub fn publish(&self, topic: &str, frame: Bytes) -> usize {
+        let connections = self.connections.lock().unwrap();
+        connections.iter()
+            .filter(|(_, connection)| connection.topics.contains(topic))
+            .filter(|(connection_id, connection)| connection.try_queue(**connection_id, frame.clone()))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> PeerAddr {
+        PeerAddr::Tcp("127.0.0.1:1234".parse().unwrap())
+    }
+
+    #[test]
+    fn test_broadcast_skips_sender_and_full_queues() {
+        let registry = ConnectionRegistry::default();
+        let mut first = registry.register(1, peer(), None);
+        let mut second = registry.register(2, peer(), None);
+
+        assert_eq!(registry.broadcast(Bytes::from_static(b"hi\n"), Some(1)), 1);
+        assert!(first.outbound_rx.try_recv().is_err());
+        assert_eq!(second.outbound_rx.try_recv().unwrap(), "hi\n");
+
+        // Slow client queue fills up, other clients keep receiving
+        for _ in 0..OUTBOUND_QUEUE_CAPACITY {
+            registry.broadcast(Bytes::from_static(b"flood\n"), Some(2));
+        }
+        assert_eq!(registry.broadcast(Bytes::from_static(b"more\n"), None), 1);
+        assert_eq!(second.outbound_rx.try_recv().unwrap(), "more\n");
+    }
+
+    #[test]
+    fn test_publish_reaches_only_subscribers() {
+        let registry = ConnectionRegistry::default();
+        let mut first = registry.register(1, peer(), None);
+        let mut second = registry.register(2, peer(), None);
+
+        registry.subscribe(1, "news");
+        registry.subscribe(2, "news");
+        registry.subscribe(2, "sport");
+        registry.unsubscribe(2, "news");
+
+        assert_eq!(registry.publish("news", Bytes::from_static(b"a\n")), 1);
+        assert_eq!(registry.publish("sport", Bytes::from_static(b"b\n")), 1);
+        assert_eq!(registry.publish("weather", Bytes::from_static(b"c\n")), 0);
+        assert_eq!(first.outbound_rx.try_recv().unwrap(), "a\n");
+        assert!(first.outbound_rx.try_recv().is_err());
+        assert_eq!(second.outbound_rx.try_recv().unwrap(), "b\n");
+        assert!(second.outbound_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_dropped_registration_sends_leave_notice() {
+        let registry = ConnectionRegistry::default();
+        let mut staying = registry.register(1, peer(), None);
+        let leaving = registry.register(2, peer(), Some(Bytes::from_static(b"LEFT 2\n")));
+
+        drop(leaving);
+        assert_eq!(staying.outbound_rx.try_recv().unwrap(), "LEFT 2\n");
+        assert_eq!(registry.broadcast(Bytes::from_static(b"hi\n"), None), 1);
+        assert_eq!(registry.list().len(), 1);
+    }
+
+    #[test]
+    fn test_kick_delivers_farewell_once() {
+        let registry = ConnectionRegistry::default();
+        let mut kicked = registry.register(1, peer(), None);
+
+        assert!(registry.kick(1, Some("bye".to_string())));
+        assert!(!registry.kick(1, None));
+        assert!(!registry.kick(2, None));
+        assert_eq!(kicked.kick_rx.try_recv().unwrap(), Some("bye".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_counted_stream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let registry = ConnectionRegistry::default();
+        let registration = registry.register(1, peer(), None);
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut client = CountedStream::new(client, registration.stats.clone());
+
+        client.write_all(b"hello\n").await.unwrap();
+        server.write_all(b"hi\n").await.unwrap();
+        let mut response = [0; 3];
+        client.read_exact(&mut response).await.unwrap();
+        registration.stats.message_sent();
+
+        let info = &registry.list()[0];
+        assert_eq!((info.bytes_sent, info.bytes_received, info.messages_sent, info.messages_received), (6, 3, 1, 0));
+    }
+}
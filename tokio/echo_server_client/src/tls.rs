@@ -0,0 +1,133 @@
+use std::{path::Path, sync::Arc};
+
+pub use tokio_rustls::rustls;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsConfigError {
+    #[error("IoError, reason='{0}'")]
+    IoError(#[from] std::io::Error),
+
+    #[error("RustlsError, reason='{0}'")]
+    RustlsError(#[from] rustls::Error),
+
+    #[error("NoCertificates, path='{0}'")]
+    NoCertificates(String),
+
+    #[error("NoPrivateKey, path='{0}'")]
+    NoPrivateKey(String),
+
+    #[error("InvalidServerName, name='{0}'")]
+    InvalidServerName(String),
+}
+
+/// Read all certificates from PEM file
+pub fn load_certs<P: AsRef<Path>>(path: P) -> Result<Vec<CertificateDer<'static>>, TlsConfigError> {
+    let path = path.as_ref();
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+
+    if certs.is_empty() {
+        return Err(TlsConfigError::NoCertificates(path.display().to_string()));
+    }
+    Ok(certs)
+}
+
+/// Read first private key (PKCS#1, PKCS#8 or SEC1) from PEM file
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> Result<PrivateKeyDer<'static>, TlsConfigError> {
+    let path = path.as_ref();
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| TlsConfigError::NoPrivateKey(path.display().to_string()))
+}
+
+/// Build root store trusting every certificate found in PEM file
+pub fn load_root_store<P: AsRef<Path>>(path: P) -> Result<rustls::RootCertStore, TlsConfigError> {
+    let mut root_store = rustls::RootCertStore::empty();
+    for cert in load_certs(path)? {
+        root_store.add(cert)?;
+    }
+    Ok(root_store)
+}
+
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+pub(crate) fn make_acceptor<P: AsRef<Path>>(cert_path: P, key_path: P) -> Result<tokio_rustls::TlsAcceptor, TlsConfigError> {
+    let config = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert_path)?, load_private_key(key_path)?)?;
+
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+}
+
+pub(crate) fn make_connector(root_store: rustls::RootCertStore) -> Result<tokio_rustls::TlsConnector, TlsConfigError> {
+    let config = rustls::ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    Ok(tokio_rustls::TlsConnector::from(Arc::new(config)))
+}
+
+pub(crate) fn make_server_name(server_name: &str) -> Result<ServerName<'static>, TlsConfigError> {
+    ServerName::try_from(server_name.to_string())
+        .map_err(|_| TlsConfigError::InvalidServerName(server_name.to_string()))
+}
+
+/// Self-signed certificates written to temporary directory, used by tests
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::path::PathBuf;
+
+    pub struct SelfSignedPem {
+        pub cert_path: PathBuf,
+        pub key_path: PathBuf,
+    }
+
+    impl SelfSignedPem {
+        pub fn generate(name: &str) -> Self {
+            let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+            let dir = std::env::temp_dir().join(format!("echo_server_client_{}_{name}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let cert_path = dir.join("cert.pem");
+            let key_path = dir.join("key.pem");
+            std::fs::write(&cert_path, cert.pem()).unwrap();
+            std::fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+
+            Self { cert_path, key_path }
+        }
+    }
+
+    impl Drop for SelfSignedPem {
+        fn drop(&mut self) {
+            if let Some(dir) = self.cert_path.parent() {
+                let _ = std::fs::remove_dir_all(dir);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_generated_pem() {
+        let pem = testing::SelfSignedPem::generate("load_generated_pem");
+        assert_eq!(load_certs(&pem.cert_path).unwrap().len(), 1);
+        assert!(load_private_key(&pem.key_path).is_ok());
+        assert_eq!(load_root_store(&pem.cert_path).unwrap().len(), 1);
+        assert!(make_acceptor(&pem.cert_path, &pem.key_path).is_ok());
+    }
+
+    #[test]
+    fn test_missing_key_in_pem() {
+        let pem = testing::SelfSignedPem::generate("missing_key_in_pem");
+        assert!(matches!(load_private_key(&pem.cert_path), Err(TlsConfigError::NoPrivateKey(_))));
+    }
+}
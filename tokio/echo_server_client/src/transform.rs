@@ -0,0 +1,110 @@
+use std::{collections::HashMap, sync::Arc};
+use bytes::Bytes;
+
+use rust_common::example_strings::{is_palimdrom, reverse_string};
+
+pub type TransformFn = dyn Fn(&str) -> String + 'static + Send + Sync;
+
+/// Name of transform which replies with unchanged text
+pub const ECHO_TRANSFORM: &str = "echo";
+
+/// Reply sent by palindrome transform for palindromes
+pub const PALINDROME_VERDICT: &str = "PALINDROME";
+
+/// Reply sent by palindrome transform for anything else
+pub const NOT_PALINDROME_VERDICT: &str = "NOT_PALINDROME";
+
+/// Named text transforms which server can apply to replies
+#[derive(Clone)]
+pub struct TransformRegistry {
+    transforms: HashMap<String, Arc<TransformFn>>,
+}
+
+impl Default for TransformRegistry {
+    fn default() -> Self {
+        Self::empty()
+            .with_transform(ECHO_TRANSFORM, str::to_string)
+            .with_transform("reverse", reverse_string)
+            .with_transform("uppercase", str::to_uppercase)
+            .with_transform("rot13", rot13)
+            .with_transform("palindrome", |text| {
+                let verdict = if is_palimdrom(text) { PALINDROME_VERDICT } else { NOT_PALINDROME_VERDICT };
+                verdict.to_string()
+            })
+    }
+}
+
+impl TransformRegistry {
+    /// Registry without any transforms, not even echo
+    pub fn empty() -> Self {
+        Self {
+            transforms: HashMap::new(),
+        }
+    }
+
+    /// Add transform or replace existing one with the same name
+    pub fn with_transform<F: Fn(&str) -> String + 'static + Send + Sync>(mut self, name: &str, transform: F) -> Self {
+        self.transforms.insert(name.to_string(), Arc::new(transform));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<TransformFn>> {
+        self.transforms.get(name).cloned()
+    }
+}
+
+fn rot13(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            'a'..='z' => ((c as u8 - b'a' + 13) % 26 + b'a') as char,
+            'A'..='Z' => ((c as u8 - b'A' + 13) % 26 + b'A') as char,
+            _ => c,
+        })
+        .collect()
+}
+
+/// Transform text payload keeping its trailing newline, payloads which are not UTF-8 are returned unchanged
+pub(crate) fn apply_transform(transform: &TransformFn, payload: &Bytes) -> Bytes {
+    let Ok(text) = std::str::from_utf8(payload) else {
+        return payload.clone();
+    };
+
+    match text.strip_suffix('\n') {
+        Some(line) => Bytes::from(transform(line) + "\n"),
+        None => Bytes::from(transform(text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(name: &str, payload: &'static str) -> Bytes {
+        let registry = TransformRegistry::default();
+        apply_transform(registry.get(name).unwrap().as_ref(), &Bytes::from(payload))
+    }
+
+    #[test]
+    fn test_builtin_transforms() {
+        assert_eq!(transform("echo", "Hello\n"), "Hello\n");
+        assert_eq!(transform("reverse", "Hello\n"), "olleH\n");
+        assert_eq!(transform("uppercase", "Hello"), "HELLO");
+        assert_eq!(transform("rot13", "Hello, World!\n"), "Uryyb, Jbeyq!\n");
+        assert_eq!(transform("palindrome", "kajak\n"), "PALINDROME\n");
+        assert_eq!(transform("palindrome", "kayak1\n"), "NOT_PALINDROME\n");
+    }
+
+    #[test]
+    fn test_binary_payload_unchanged() {
+        let registry = TransformRegistry::default();
+        let payload = Bytes::from_static(b"\xff\xfe\n");
+        assert_eq!(apply_transform(registry.get("uppercase").unwrap().as_ref(), &payload), payload);
+    }
+
+    #[test]
+    fn test_custom_transform() {
+        let registry = TransformRegistry::empty().with_transform("shout", |text| format!("{text}!"));
+        assert!(registry.get(ECHO_TRANSFORM).is_none());
+        assert_eq!(registry.get("shout").unwrap()("hey"), "hey!");
+    }
+}
@@ -0,0 +1,279 @@
+use std::{pin::Pin, task::{Context, Poll}};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Credentials of process on the other side of Unix domain socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+/// Identity of connected client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerAddr {
+    Tcp(std::net::SocketAddr),
+    Unix(PeerCredentials),
+    /// Sender of datagram received by UDP server
+    Udp(std::net::SocketAddr),
+}
+
+impl std::fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddr::Tcp(address) | PeerAddr::Udp(address) => write!(f, "{address}"),
+            PeerAddr::Unix(PeerCredentials { uid, gid, pid: Some(pid) }) => write!(f, "uid={uid},gid={gid},pid={pid}"),
+            PeerAddr::Unix(PeerCredentials { uid, gid, pid: None }) => write!(f, "uid={uid},gid={gid}"),
+        }
+    }
+}
+
+/// Connected stream of any supported transport
+pub(crate) enum Transport {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Transport::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Transport::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Transport::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Transport::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Socket file which is removed together with listener
+#[cfg(unix)]
+pub(crate) struct UnixSocketPath(PathBuf);
+
+#[cfg(unix)]
+impl Drop for UnixSocketPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Bind socket in directory accessible only by owner and move it into place once it has requested mode,
+/// so no other user can connect while socket still has default permissions
+#[cfg(unix)]
+fn bind_unix_with_mode(path: &Path, mode: u32) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let file_name = path.file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "socket path has no file name"))?;
+    // Private socket path is longer than path by pid and 9 characters, binding fails when that exceeds socket path length limit
+    let private_dir = path.with_file_name(format!(".{}.{}.bind", file_name.to_string_lossy(), std::process::id()));
+    let private_path = private_dir.join("s");
+    // Left behind by crashed process which had the same pid
+    let _ = std::fs::remove_file(&private_path);
+    let _ = std::fs::remove_dir(&private_dir);
+    std::fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+
+    let result = (|| -> std::io::Result<_> {
+        let listener = tokio::net::UnixListener::bind(&private_path)?;
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    })();
+    let _ = std::fs::remove_file(&private_path);
+    let _ = std::fs::remove_dir(&private_dir);
+    result
+}
+
+pub(crate) enum Listener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: tokio::net::UnixListener,
+        path: UnixSocketPath,
+    },
+}
+
+impl Listener {
+    #[cfg(unix)]
+    pub fn bind_unix(path: &Path, mode: Option<u32>) -> std::io::Result<Self> {
+        remove_stale_socket(path)?;
+        let listener = match mode {
+            Some(mode) => bind_unix_with_mode(path, mode)?,
+            None => tokio::net::UnixListener::bind(path)?,
+        };
+        Ok(Listener::Unix {
+            listener,
+            path: UnixSocketPath(path.to_path_buf()),
+        })
+    }
+
+    pub async fn accept(&self) -> std::io::Result<(Transport, PeerAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, address) = listener.accept().await?;
+                Ok((Transport::Tcp(stream), PeerAddr::Tcp(address)))
+            },
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => {
+                let (stream, _) = listener.accept().await?;
+                let credentials = stream.peer_cred()?;
+                let peer = PeerAddr::Unix(PeerCredentials {
+                    uid: credentials.uid(),
+                    gid: credentials.gid(),
+                    pid: credentials.pid(),
+                });
+                Ok((Transport::Unix(stream), peer))
+            },
+        }
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr(),
+            #[cfg(unix)]
+            Listener::Unix { .. } => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Unix socket has no IP address")),
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn local_path(&self) -> Option<&Path> {
+        match self {
+            Listener::Tcp(_) => None,
+            Listener::Unix { path, .. } => Some(&path.0),
+        }
+    }
+}
+
+/// Remove socket file left by server which did not clean up, socket still accepting connections is kept
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())))
+        },
+        Ok(_) => match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => Err(std::io::Error::new(std::io::ErrorKind::AddrInUse, format!("{} is used by running server", path.display()))),
+            Err(_) => {
+                tracing::info!(path = %path.display(), "Removing stale socket");
+                std::fs::remove_file(path)
+            },
+        },
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("echo_server_client_{}_{name}.sock", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_stale_socket_is_replaced() {
+        let path = socket_path("stale_socket_is_replaced");
+        // Listener dropped without cleanup leaves socket file behind
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = Listener::bind_unix(&path, None).unwrap();
+        assert_eq!(listener.local_path(), Some(path.as_path()));
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_socket_in_use_is_kept() {
+        let path = socket_path("socket_in_use_is_kept");
+        let _listener = Listener::bind_unix(&path, None).unwrap();
+
+        let result = Listener::bind_unix(&path, None);
+        assert_eq!(result.err().map(|e| e.kind()), Some(std::io::ErrorKind::AddrInUse));
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_regular_file_is_kept() {
+        let path = socket_path("regular_file_is_kept");
+        std::fs::write(&path, b"data").unwrap();
+
+        let result = Listener::bind_unix(&path, None);
+        assert_eq!(result.err().map(|e| e.kind()), Some(std::io::ErrorKind::AlreadyExists));
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socket_bound_with_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = socket_path("bound_with_permissions");
+        let listener = Listener::bind_unix(&path, Some(0o600)).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(std::os::unix::net::UnixStream::connect(&path).is_ok());
+
+        // Private directory used for binding is removed
+        let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let leftovers = std::fs::read_dir(std::env::temp_dir()).unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&format!(".{file_name}")))
+            .count();
+        assert_eq!(leftovers, 0);
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_leftover_private_directory_is_replaced() {
+        let path = socket_path("leftover_private_dir");
+        let private_dir = path.with_file_name(format!(".{}.{}.bind", path.file_name().unwrap().to_string_lossy(), std::process::id()));
+        std::fs::create_dir(&private_dir).unwrap();
+        drop(std::os::unix::net::UnixListener::bind(private_dir.join("s")).unwrap());
+
+        let listener = Listener::bind_unix(&path, Some(0o600)).unwrap();
+        assert!(path.exists());
+        assert!(!private_dir.exists());
+        drop(listener);
+    }
+
+    #[test]
+    fn test_peer_addr_display() {
+        let credentials = PeerCredentials { uid: 1000, gid: 100, pid: Some(42) };
+        assert_eq!(PeerAddr::Unix(credentials).to_string(), "uid=1000,gid=100,pid=42");
+        assert_eq!(PeerAddr::Tcp("127.0.0.1:80".parse().unwrap()).to_string(), "127.0.0.1:80");
+    }
+}
@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use crate::echo_client::EchoClientError;
+
+/// Largest payload which fits into single UDP datagram
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Datagram counterpart of EchoClient, lost datagrams are retransmitted after timeout
+pub struct UdpEchoClient {
+    socket: tokio::net::UdpSocket,
+    retransmissions: u32,
+}
+
+impl UdpEchoClient {
+    pub async fn new<A: tokio::net::ToSocketAddrs>(addr: A) -> Result<Self, EchoClientError> {
+        let server_address = tokio::net::lookup_host(addr).await?
+            .next()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to connect to"))?;
+
+        let local_address = match server_address {
+            std::net::SocketAddr::V4(_) => "0.0.0.0:0",
+            std::net::SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = tokio::net::UdpSocket::bind(local_address).await?;
+        socket.connect(server_address).await?;
+
+        Ok(Self {
+            socket,
+            retransmissions: 2,
+        })
+    }
+
+    /// How many times message is sent again when echo does not arrive within timeout
+    pub fn with_retransmissions(mut self, retransmissions: u32) -> Self {
+        self.retransmissions = retransmissions;
+        self
+    }
+
+    pub async fn send_await(
+        &mut self,
+        timeout: Option<Duration>,
+        msg: &str
+    ) -> Result<(), EchoClientError> {
+        self.send_await_bytes(timeout, msg.as_bytes()).await
+    }
+
+    /// Send datagram and wait for the same bytes to be echoed back.
+    /// Without timeout message is sent once and echo is awaited forever.
+    pub async fn send_await_bytes(
+        &mut self,
+        timeout: Option<Duration>,
+        msg: &[u8]
+    ) -> Result<(), EchoClientError> {
+        let Some(timeout_duration) = timeout else {
+            self.socket.send(msg).await?;
+            return self.await_echo(msg).await;
+        };
+
+        let attempts = self.retransmissions + 1;
+        for attempt in 1..=attempts {
+            self.socket.send(msg).await?;
+            match tokio::time::timeout(timeout_duration, self.await_echo(msg)).await {
+                Ok(result) => return result,
+                Err(_) => tracing::debug!(timeout = ?timeout_duration, attempt, attempts, "No echo within timeout"),
+            }
+        }
+
+        Err(EchoClientError::NoResponse { attempts })
+    }
+
+    /// Wait for datagram equal to msg, late echoes of previous messages are skipped
+    async fn await_echo(&self, msg: &[u8]) -> Result<(), EchoClientError> {
+        let mut response = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let length = self.socket.recv(&mut response).await?;
+            if &response[..length] == msg {
+                return Ok(());
+            }
+            tracing::debug!(length, "Skipping unexpected datagram");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Echo peer which ignores first `dropped` datagrams, returns how many datagrams it received
+    async fn lossy_echo_peer(dropped: usize) -> (std::net::SocketAddr, tokio::task::JoinHandle<usize>) {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+
+        let handle = tokio::spawn(async move {
+            let mut datagram = vec![0; MAX_DATAGRAM_SIZE];
+            let mut received = 0;
+            while let Ok(Ok((length, client_addr))) = tokio::time::timeout(Duration::from_millis(300), socket.recv_from(&mut datagram)).await {
+                received += 1;
+                if received > dropped {
+                    socket.send_to(&datagram[..length], client_addr).await.unwrap();
+                }
+            }
+            received
+        });
+
+        (address, handle)
+    }
+
+    #[tokio::test]
+    async fn test_retransmission_after_lost_datagram() {
+        let (peer_address, peer_handle) = lossy_echo_peer(2).await;
+
+        let mut client = UdpEchoClient::new(peer_address).await.unwrap();
+        client.send_await(Some(Duration::from_millis(50)), "Hello world").await.unwrap();
+
+        assert_eq!(peer_handle.await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_no_response_after_all_attempts() {
+        let (peer_address, peer_handle) = lossy_echo_peer(usize::MAX).await;
+
+        let mut client = UdpEchoClient::new(peer_address).await.unwrap().with_retransmissions(3);
+        let result = client.send_await(Some(Duration::from_millis(20)), "Hello world").await;
+        assert!(matches!(result, Err(EchoClientError::NoResponse { attempts: 4 })));
+
+        assert_eq!(peer_handle.await.unwrap(), 4);
+    }
+}
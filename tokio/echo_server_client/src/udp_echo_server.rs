@@ -0,0 +1,446 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use bytes::Bytes;
+
+use crate::{
+    echo_server::{EchoMessage, EchoServerError, MessageMode, MessageQueue, QueueOverflowPolicy},
+    hook::{run_hooks, HookAction, MessageHook},
+    transport::PeerAddr,
+};
+
+/// Largest payload which fits into single UDP datagram
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const DEFAULT_MAX_PEERS: usize = 1024;
+const DEFAULT_PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Datagram counterpart of EchoServer, every datagram is a single message.
+/// Every sender address gets its own connection id with its first datagram, sequence counts datagrams from that address.
+/// Address which was forgotten as idle or to make room for others gets new connection id when it sends again.
+pub struct UdpEchoServer {
+    socket: tokio::net::UdpSocket,
+    queue_capacity: usize,
+    queue_overflow_policy: QueueOverflowPolicy,
+    msg_handlers: Vec<Box<dyn MessageHook>>,
+    message_mode: MessageMode,
+    max_peers: usize,
+    peer_idle_timeout: Duration,
+}
+
+pub struct UdpEchoServerHandler {
+    shutdown_tx: tokio::sync::oneshot::Sender<()>,
+    task_handler: tokio::task::JoinHandle<()>,
+    msg_rx: Arc<tokio::sync::Mutex<tokio::sync::mpsc::Receiver<EchoMessage>>>,
+}
+
+/// Identity assigned to sender address
+struct DatagramPeer {
+    connection_id: u64,
+    next_sequence: u64,
+    last_seen: tokio::time::Instant,
+}
+
+/// Sender addresses remembered by server, bounded so spoofed addresses cannot exhaust memory
+struct DatagramPeers {
+    peers: HashMap<std::net::SocketAddr, DatagramPeer>,
+    next_connection_id: u64,
+    max_peers: usize,
+    idle_timeout: Duration,
+}
+
+impl DatagramPeers {
+    fn new(max_peers: usize, idle_timeout: Duration) -> Self {
+        Self {
+            peers: HashMap::new(),
+            next_connection_id: 1,
+            max_peers,
+            idle_timeout,
+        }
+    }
+
+    /// Find peer for address or start new one, idle peers are forgotten first and least recently seen one when all are active
+    fn touch(&mut self, address: std::net::SocketAddr) -> &mut DatagramPeer {
+        let now = tokio::time::Instant::now();
+        let idle_timeout = self.idle_timeout;
+        if self.peers.get(&address).is_some_and(|peer| now - peer.last_seen >= idle_timeout) {
+            self.peers.remove(&address);
+        }
+        if !self.peers.contains_key(&address) && self.peers.len() >= self.max_peers {
+            self.peers.retain(|_, peer| now - peer.last_seen < idle_timeout);
+            if self.peers.len() >= self.max_peers {
+                let oldest = self.peers.iter().min_by_key(|(_, peer)| peer.last_seen).map(|(address, _)| *address);
+                if let Some(oldest) = oldest {
+                    tracing::debug!(peer = %oldest, "Forgetting least recently seen peer");
+                    self.peers.remove(&oldest);
+                }
+            }
+        }
+
+        let next_connection_id = &mut self.next_connection_id;
+        let peer = self.peers.entry(address).or_insert_with(|| {
+            let connection_id = *next_connection_id;
+            *next_connection_id += 1;
+            DatagramPeer { connection_id, next_sequence: 0, last_seen: now }
+        });
+        peer.last_seen = now;
+        peer
+    }
+}
+
+impl UdpEchoServer {
+    /// Bind socket to address ready to be started
+    pub async fn bind<A: tokio::net::ToSocketAddrs>(addr: A) -> Result<Self, EchoServerError> {
+        Ok(Self {
+            socket: tokio::net::UdpSocket::bind(addr).await?,
+            queue_capacity: 32,
+            queue_overflow_policy: QueueOverflowPolicy::DropNewest,
+            msg_handlers: Vec::new(),
+            message_mode: MessageMode::Text,
+            max_peers: DEFAULT_MAX_PEERS,
+            peer_idle_timeout: DEFAULT_PEER_IDLE_TIMEOUT,
+        })
+    }
+
+    /// Append hook to chain called for every datagram, same hooks as for EchoServer can be used.
+    /// There is no connection to close, so closing by hook only suppresses reply.
+    pub fn with_listener<H: MessageHook>(mut self, msg_handler: H) -> Self {
+        self.msg_handlers.push(Box::new(msg_handler));
+        self
+    }
+
+    /// Set incoming messages queue size and what happens when it is full.
+    /// Datagram is dropped without reply instead of disconnecting client, blocking stops receiving datagrams.
+    pub fn with_queue_capacity(mut self, queue_capacity: usize, policy: QueueOverflowPolicy) -> Self {
+        self.queue_capacity = queue_capacity;
+        self.queue_overflow_policy = policy;
+        self
+    }
+
+    /// Select whether datagrams are validated as UTF-8 text or treated as raw bytes
+    pub fn with_message_mode(mut self, message_mode: MessageMode) -> Self {
+        self.message_mode = message_mode;
+        self
+    }
+
+    /// Limit how many sender addresses are remembered, least recently seen one is forgotten to make room
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = max_peers.max(1);
+        self
+    }
+
+    /// Forget sender address which sent nothing for this long
+    pub fn with_peer_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.peer_idle_timeout = idle_timeout;
+        self
+    }
+
+    pub async fn bind_any_local() -> Result<Self, EchoServerError> {
+        Self::bind("127.0.0.1:0").await
+    }
+
+    pub fn get_local_address(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Run receiving in background
+    #[must_use = "UdpEchoServerHandler must be stored to keep the server alive"]
+    pub fn run(self) -> Result<UdpEchoServerHandler, EchoServerError> {
+        let address = self.get_local_address()?;
+        tracing::info!(%address, "Started UDP echo server");
+
+        let (shutdown_tx, mut shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let queue = MessageQueue::new(self.queue_capacity, self.queue_overflow_policy);
+        let msg_rx = queue.receiver();
+
+        let task_handler = tokio::spawn(async move {
+            let mut datagram = vec![0; MAX_DATAGRAM_SIZE];
+            let mut peers = DatagramPeers::new(self.max_peers, self.peer_idle_timeout);
+
+            loop {
+                let (length, client_addr) = tokio::select! {
+                    _ = &mut shutdown_rx => {
+                        tracing::debug!("Got shutdown signal");
+                        break;
+                    },
+                    received = self.socket.recv_from(&mut datagram) => match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            // ICMP port unreachable from previous reply lands here on some platforms
+                            tracing::warn!(error = %e, "Receiving datagram failed");
+                            continue;
+                        },
+                    },
+                };
+
+                let payload = Bytes::copy_from_slice(&datagram[..length]);
+                if self.message_mode == MessageMode::Text && std::str::from_utf8(&payload).is_err() {
+                    tracing::debug!(%client_addr, "Dropping datagram, not valid UTF-8");
+                    continue;
+                }
+
+                let peer = peers.touch(client_addr);
+                let message = EchoMessage {
+                    connection_id: peer.connection_id,
+                    peer_addr: PeerAddr::Udp(client_addr),
+                    received_at: std::time::SystemTime::now(),
+                    sequence: peer.next_sequence,
+                    payload: payload.clone(),
+                };
+                peer.next_sequence += 1;
+
+                // Blocked on full queue server still has to stop
+                let queued = tokio::select! {
+                    _ = &mut shutdown_rx => {
+                        tracing::debug!("Got shutdown signal");
+                        break;
+                    },
+                    queued = queue.push(message.clone()) => queued,
+                };
+                if let Err(overflow) = queued {
+                    tracing::debug!(%client_addr, dropped = overflow.dropped, policy = ?queue.policy(), "Queue full");
+                    if queue.policy() == QueueOverflowPolicy::Disconnect {
+                        continue;
+                    }
+                }
+
+                let reply = match run_hooks(&self.msg_handlers, &message, payload).await {
+                    HookAction::Reply(reply) => reply,
+                    HookAction::Suppress | HookAction::Close => continue,
+                };
+
+                if let Err(e) = self.socket.send_to(&reply, client_addr).await {
+                    tracing::warn!(%client_addr, error = %e, "Couldnt write back to client");
+                }
+            }
+        });
+
+        Ok(UdpEchoServerHandler {
+            shutdown_tx,
+            task_handler,
+            msg_rx,
+        })
+    }
+}
+
+impl UdpEchoServerHandler {
+    pub async fn shutdown(self) -> Result<(), EchoServerError> {
+        tracing::info!("Shutting down UDP server");
+        self.shutdown_tx.send(()).map_err(|_| EchoServerError::KillFailed)?;
+        self.task_handler.await.map_err(|_| EchoServerError::KillFailed)
+    }
+
+    pub async fn await_incomming_msg(&mut self, duration: Option<Duration>) -> Result<Option<EchoMessage>, tokio::time::error::Elapsed> {
+        let receive = async { self.msg_rx.lock().await.recv().await };
+        if let Some(timeout_duration) = duration {
+            tokio::time::timeout(timeout_duration, receive).await
+        } else {
+            Ok(receive.await)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn client_make_requests(server_address: std::net::SocketAddr, requests: &[&[u8]]) {
+        let client_socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client_socket.connect(server_address).await.unwrap();
+        let mut response = vec![0; MAX_DATAGRAM_SIZE];
+
+        for &request in requests {
+            client_socket.send(request).await.unwrap();
+            let length = tokio::time::timeout(Duration::from_millis(500), client_socket.recv(&mut response)).await.unwrap().unwrap();
+            assert_eq!(request, &response[..length]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_echo_with_queue_and_hook() {
+        let messages_counter = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let messages_counter_copy = messages_counter.clone();
+
+        let echo_server = UdpEchoServer::bind_any_local().await
+            .unwrap()
+            .with_listener(move |msg: &EchoMessage| {
+                let value = messages_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                tracing::debug!(client = %msg.peer_addr, payload = %String::from_utf8_lossy(&msg.payload), count = value, "Hook got datagram");
+            });
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut echo_server_handle = echo_server.run().unwrap();
+
+        client_make_requests(server_address, &[b"message", b"multi\nline", b""]).await;
+
+        for (sequence, expected) in ["message", "multi\nline", ""].into_iter().enumerate() {
+            let msg = echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap();
+            assert_eq!((msg.connection_id, msg.sequence, msg.payload.as_ref()), (1, sequence as u64, expected.as_bytes()));
+            assert!(matches!(msg.peer_addr, PeerAddr::Udp(_)));
+        }
+        assert!(echo_server_handle.await_incomming_msg(Some(Duration::from_millis(100))).await.is_err());
+
+        echo_server_handle.shutdown().await.unwrap();
+        assert_eq!(messages_counter_copy.load(std::sync::atomic::Ordering::Relaxed), 3);
+    }
+
+    struct ShoutOrDrop;
+
+    #[crate::hook::async_trait]
+    impl MessageHook for ShoutOrDrop {
+        async fn on_message(&self, message: &EchoMessage, reply: Bytes) -> HookAction {
+            match message.payload.as_ref() {
+                b"drop" => HookAction::Suppress,
+                b"close" => HookAction::Close,
+                _ => HookAction::Reply(Bytes::from(String::from_utf8_lossy(&reply).to_uppercase())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_hooks_chain_and_peers() {
+        let echo_server = UdpEchoServer::bind_any_local().await
+            .unwrap()
+            .with_listener(ShoutOrDrop);
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut echo_server_handle = echo_server.run().unwrap();
+        let timeout = Duration::from_millis(100);
+        let mut response = vec![0; MAX_DATAGRAM_SIZE];
+
+        let first = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let second = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for (client, request) in [(&first, "hello"), (&second, "drop"), (&first, "close"), (&second, "again")] {
+            client.send_to(request.as_bytes(), server_address).await.unwrap();
+        }
+        let length = tokio::time::timeout(timeout, first.recv(&mut response)).await.unwrap().unwrap();
+        assert_eq!(&response[..length], b"HELLO");
+        let length = tokio::time::timeout(timeout, second.recv(&mut response)).await.unwrap().unwrap();
+        assert_eq!(&response[..length], b"AGAIN");
+        assert!(tokio::time::timeout(timeout, first.recv(&mut response)).await.is_err());
+
+        // Suppressed datagrams are still queued, every sender address has its own id and sequence
+        let first_addr = PeerAddr::Udp(first.local_addr().unwrap());
+        let second_addr = PeerAddr::Udp(second.local_addr().unwrap());
+        for (peer_addr, connection_id, sequence, payload) in [(&first_addr, 1, 0, "hello"), (&second_addr, 2, 0, "drop"), (&first_addr, 1, 1, "close"), (&second_addr, 2, 1, "again")] {
+            let msg = echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap();
+            assert_eq!((&msg.peer_addr, msg.connection_id, msg.sequence), (peer_addr, connection_id, sequence));
+            assert_eq!(msg.payload, payload);
+        }
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peers_are_bounded() {
+        let addresses: Vec<std::net::SocketAddr> = (1..=3).map(|port| ([127, 0, 0, 1], port).into()).collect();
+        let mut peers = DatagramPeers::new(2, Duration::from_secs(60));
+        assert_eq!(peers.touch(addresses[0]).connection_id, 1);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(peers.touch(addresses[1]).connection_id, 2);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        peers.touch(addresses[0]).next_sequence += 1;
+
+        // Second address is least recently seen and makes room for third
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(peers.touch(addresses[2]).connection_id, 3);
+        assert_eq!(peers.peers.len(), 2);
+        assert_eq!(peers.touch(addresses[0]).next_sequence, 1);
+        assert_eq!(peers.touch(addresses[1]).connection_id, 4);
+        assert_eq!(peers.peers.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_udp_idle_peer_gets_new_connection_id() {
+        let echo_server = UdpEchoServer::bind_any_local().await
+            .unwrap()
+            .with_peer_idle_timeout(Duration::from_millis(50));
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut echo_server_handle = echo_server.run().unwrap();
+
+        let client_socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut response = vec![0; MAX_DATAGRAM_SIZE];
+        for (request, pause) in [("first", 0), ("second", 100), ("third", 0)] {
+            tokio::time::sleep(Duration::from_millis(pause)).await;
+            client_socket.send_to(request.as_bytes(), server_address).await.unwrap();
+            tokio::time::timeout(Duration::from_millis(500), client_socket.recv(&mut response)).await.unwrap().unwrap();
+        }
+
+        // Address idle for longer than timeout starts over, active one keeps counting
+        for (connection_id, sequence) in [(1, 0), (2, 0), (2, 1)] {
+            let msg = echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap();
+            assert_eq!((msg.connection_id, msg.sequence), (connection_id, sequence));
+        }
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_udp_queue_overflow_policies() {
+        for (policy, replies, queued) in [
+            (QueueOverflowPolicy::DropNewest, 3, ["1", "2"]),
+            (QueueOverflowPolicy::DropOldest, 3, ["2", "3"]),
+            (QueueOverflowPolicy::Disconnect, 2, ["1", "2"]),
+        ] {
+            let echo_server = UdpEchoServer::bind_any_local().await
+                .unwrap()
+                .with_queue_capacity(2, policy);
+            let server_address = echo_server.get_local_address().unwrap();
+            let mut echo_server_handle = echo_server.run().unwrap();
+
+            let client_socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut response = vec![0; MAX_DATAGRAM_SIZE];
+            let mut received = 0;
+            for request in ["1", "2", "3"] {
+                client_socket.send_to(request.as_bytes(), server_address).await.unwrap();
+                if tokio::time::timeout(Duration::from_millis(100), client_socket.recv(&mut response)).await.is_ok() {
+                    received += 1;
+                }
+            }
+            assert_eq!(received, replies, "{policy:?}");
+
+            for expected in queued {
+                assert_eq!(echo_server_handle.await_incomming_msg(None).await.unwrap().unwrap().payload, expected, "{policy:?}");
+            }
+            echo_server_handle.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_shutdown_while_blocked_on_queue() {
+        let echo_server = UdpEchoServer::bind_any_local().await
+            .unwrap()
+            .with_queue_capacity(1, QueueOverflowPolicy::Block);
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        let client_socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client_socket.send_to(b"1", server_address).await.unwrap();
+        client_socket.send_to(b"2", server_address).await.unwrap();
+        let mut response = vec![0; MAX_DATAGRAM_SIZE];
+        tokio::time::timeout(Duration::from_millis(100), client_socket.recv(&mut response)).await.unwrap().unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(100), client_socket.recv(&mut response)).await.is_err());
+
+        tokio::time::timeout(Duration::from_millis(500), echo_server_handle.shutdown()).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_udp_text_mode_drops_invalid_utf8() {
+        let echo_server = UdpEchoServer::bind_any_local().await.unwrap();
+        let server_address = echo_server.get_local_address().unwrap();
+        let mut echo_server_handle = echo_server.run().unwrap();
+
+        let client_socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client_socket.send_to(b"\xff\xfe", server_address).await.unwrap();
+
+        let mut response = vec![0; MAX_DATAGRAM_SIZE];
+        assert!(tokio::time::timeout(Duration::from_millis(100), client_socket.recv(&mut response)).await.is_err());
+        assert!(echo_server_handle.await_incomming_msg(Some(Duration::from_millis(10))).await.is_err());
+        echo_server_handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_udp_binary_mode() {
+        let echo_server = UdpEchoServer::bind_any_local().await
+            .unwrap()
+            .with_message_mode(MessageMode::Binary);
+        let server_address = echo_server.get_local_address().unwrap();
+        let echo_server_handle = echo_server.run().unwrap();
+
+        client_make_requests(server_address, &[b"\xff\xfe\x00", &[0x80; 1400]]).await;
+        echo_server_handle.shutdown().await.unwrap();
+    }
+}